@@ -26,7 +26,7 @@ fn main() {
 
     let addr: [u8; 5] = [0x22, 0x22, 0x22, 0x22, 0x22];
 
-    setup_nrf(&mut nrf, &addr);
+    setup_nrf(&mut nrf, &addr).expect("Failed to set up nrf");
     sleep(Duration::from_millis(10));
 
     let mut nrf = nrf
@@ -41,7 +41,7 @@ fn main() {
         while let Some(chan) = nrf.can_read().expect("Failed to check for msgs") {
             println!("Got a message on channel: {}", chan);
             let message = nrf.read().expect("Failed to read");
-            let decoded = postcard::from_bytes::<common::Message>(&message);
+            let decoded = postcard::from_bytes::<common::Packet>(&message);
 
             println!("Msg: {:?}", decoded);
             // println!("Content: {}", String::from_utf8_lossy(&message));
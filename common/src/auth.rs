@@ -0,0 +1,103 @@
+// Message authentication shared between the stations and the server. A MAC
+// covers a counter that the station increments for every message, followed
+// by the message itself, so that recorded messages can not be sent again.
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+// Bytes of the HMAC-SHA256 that are sent along with each message
+pub const MAC_LENGTH: usize = 8;
+pub const COUNTER_LENGTH: usize = 4;
+
+pub type Tag = [u8; MAC_LENGTH];
+
+pub fn sign(key: &[u8], counter: u32, message: &[u8]) -> Tag {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.update(&counter.to_be_bytes());
+    mac.update(message);
+
+    let mut tag = [0; MAC_LENGTH];
+    tag.copy_from_slice(&mac.finalize().into_bytes()[..MAC_LENGTH]);
+    tag
+}
+
+pub fn verify(key: &[u8], counter: u32, message: &[u8], tag: &[u8]) -> bool {
+    if tag.len() != MAC_LENGTH {
+        return false;
+    }
+    // Compare every byte so that the time taken does not reveal how much matched
+    sign(key, counter, message).iter()
+        .zip(tag)
+        .fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+// Appends the counter and tag to a postcard encoded message. Returns the
+// length of the signed message, or None if `buffer` is too small
+pub fn sign_in_place(key: &[u8], counter: u32, buffer: &mut [u8], length: usize) -> Option<usize> {
+    let total = length + COUNTER_LENGTH + MAC_LENGTH;
+    if buffer.len() < total {
+        return None;
+    }
+    let tag = sign(key, counter, &buffer[..length]);
+    buffer[length..length + COUNTER_LENGTH].copy_from_slice(&counter.to_be_bytes());
+    buffer[length + COUNTER_LENGTH..total].copy_from_slice(&tag);
+    Some(total)
+}
+
+// Splits the counter and tag that `sign_in_place` appended
+pub fn split_trailer(bytes: &[u8]) -> Option<(u32, &[u8])> {
+    if bytes.len() != COUNTER_LENGTH + MAC_LENGTH {
+        return None;
+    }
+    let mut counter = [0; COUNTER_LENGTH];
+    counter.copy_from_slice(&bytes[..COUNTER_LENGTH]);
+    Some((u32::from_be_bytes(counter), &bytes[COUNTER_LENGTH..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_vector() {
+        // RFC 4231 test case 2. The counter holds the first four bytes of the data
+        let counter = u32::from_be_bytes(*b"what");
+        let tag = sign(b"Jefe", counter, b" do ya want for nothing?");
+        assert_eq!(tag, [0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e]);
+    }
+
+    #[test]
+    fn signed_messages_verify() {
+        let mut buffer = [0; 32];
+        buffer[..5].copy_from_slice(b"hello");
+        let length = sign_in_place(b"secret", 7, &mut buffer, 5).unwrap();
+        assert_eq!(length, 5 + COUNTER_LENGTH + MAC_LENGTH);
+
+        let (counter, tag) = split_trailer(&buffer[5..length]).unwrap();
+        assert_eq!(counter, 7);
+        assert!(verify(b"secret", counter, b"hello", tag));
+        assert!(!verify(b"other", counter, b"hello", tag));
+        assert!(!verify(b"secret", counter, b"hellp", tag));
+    }
+
+    #[test]
+    fn wrong_counters_are_rejected() {
+        let tag = sign(b"secret", 7, b"hello");
+        assert!(!verify(b"secret", 8, b"hello", &tag));
+    }
+
+    #[test]
+    fn truncated_tags_are_rejected() {
+        let tag = sign(b"secret", 7, b"hello");
+        assert!(!verify(b"secret", 7, b"hello", &tag[..MAC_LENGTH - 1]));
+        assert!(!verify(b"secret", 7, b"hello", &[]));
+        assert!(split_trailer(&[0; COUNTER_LENGTH + MAC_LENGTH - 1]).is_none());
+    }
+
+    #[test]
+    fn small_buffers_are_not_signed() {
+        let mut buffer = [0; 16];
+        assert_eq!(sign_in_place(b"secret", 7, &mut buffer, 5), None);
+    }
+}
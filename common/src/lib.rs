@@ -5,11 +5,12 @@ use serde::{Serialize, Deserialize};
 use bmp085_driver as bno;
 
 pub mod nrf;
+pub mod auth;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Pascal(i32);
+pub struct Pascal(pub i32);
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct DeciCelcius(i32);
+pub struct DeciCelcius(pub i32);
 
 impl From<bno::DeciCelcius> for DeciCelcius {
     fn from(other: bno::DeciCelcius) -> Self {
@@ -29,8 +30,40 @@ pub enum SensorReading {
     Battery(f32),
 }
 
+// Version of the messages exchanged between the stations and the server. Bump
+// this when making changes that older servers can not decode
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// Who sent a message. Sent as a Hello once on connections, and at the start of
+// every transmission on the radio
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Header<'a> {
+    pub station: &'a str,
+    pub firmware_version: &'a str,
+    pub protocol_version: u8,
+    // Incremented by the station every time it starts
+    pub boot_counter: u32,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Message<'a> {
     Reading(SensorReading),
-    Error(&'a str)
+    Error(&'a str),
+    Hello(#[serde(borrow)] Header<'a>),
+}
+
+// Radio packets are sent as a single nRF24L01 payload, including the
+// auth::COUNTER_LENGTH + auth::MAC_LENGTH trailer of signed packets. The server
+// drops longer packets. Signed, that leaves at most 10 bytes for the station
+// name and firmware version of a Hello, and 17 for the text of an Error
+pub const MAX_PACKET_LENGTH: usize = 32;
+
+// There is no room for the header in every radio packet, so a station sends a
+// Hello with its header and the messages after it refer to that by `station`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Packet<'a> {
+    // Chosen by the station, different for every station within range
+    pub station: u8,
+    #[serde(borrow)]
+    pub message: Message<'a>,
 }
@@ -48,12 +48,20 @@ use heapless::consts;
 use heapless::{Vec, HistoryBuffer, ArrayLength};
 
 use common::nrf::setup_nrf;
-use common::{Message, SensorReading};
+use common::{Header, Message, Packet, SensorReading};
 
 use core::fmt::Write;
 
 const SLEEP_DURATION: u32 = 10;
 
+// The server tells the stations apart by this number, it has to be different
+// for every station within range
+const STATION: u8 = 1;
+// Short enough to fit in a signed Hello together with the version
+const STATION_NAME: &str = "rtic";
+// Station number, message kind and string length come before the text
+const MAX_ERROR_LENGTH: usize = common::MAX_PACKET_LENGTH - 3;
+
 #[derive(Debug)]
 pub enum Error {
     TransmitFailure,
@@ -62,6 +70,7 @@ pub enum Error {
     NrfTxError(nrf::Error<spi::Error>),
     NrfPollError(nrf::Error<spi::Error>),
     EncodingError(postcard::Error),
+    PacketTooLong(usize),
     FmtErr(core::fmt::Error),
 }
 
@@ -149,7 +158,7 @@ const APP: () = {
 
         let addr: [u8; 5] = [0x22, 0x22, 0x22, 0x22, 0x22];
 
-        setup_nrf(&mut nrf, &addr);
+        setup_nrf(&mut nrf, &addr).expect("Failed to set up the radio");
 
         delay.delay_ms(10 as u16);
 
@@ -160,11 +169,8 @@ const APP: () = {
 
             delay.delay_ms(130 as u16);
 
-            nrf.send(b"hello, world")
-                .expect("Failed to send hello world");
-            let success = block!(nrf.poll_send()).expect("Poll error");
-            if !success {
-                hprintln!("Failed to send message")
+            if let Err(e) = send_message(&Message::Hello(header()), &mut nrf) {
+                hprintln!("Failed to send hello: {:?}", e)
                     .unwrap();
                 loop {continue;}
             }
@@ -304,6 +310,8 @@ const APP: () = {
 
         match nrf.tx() {
             Ok(mut nrf) => {
+                // The server may have restarted since the last hello
+                try_or_log(send_message(&Message::Hello(header()), &mut nrf), r.errors, |x| x);
                 for message in messages {
                     // This error will most likely be unrecoverable which is why
                     // it is not stored
@@ -318,7 +326,11 @@ const APP: () = {
                     if let Err(e) = write!(s, "{:?}", error) {
                         err_transmit_err = Some(Error::FmtErr(e))
                     }
-                    if let Err(e) = send_message(&Message::Error(&s), &mut nrf) {
+                    let mut length = s.len().min(MAX_ERROR_LENGTH);
+                    while !s.is_char_boundary(length) {
+                        length -= 1;
+                    }
+                    if let Err(e) = send_message(&Message::Error(&s[..length]), &mut nrf) {
                         err_transmit_err = Some(e);
                         break
                     }
@@ -393,11 +405,25 @@ fn try_or_log<T, E>(
 }
 
 
+// There is nowhere to keep a count of the boots in, so it is always 0
+fn header() -> Header<'static> {
+    Header {
+        station: STATION_NAME,
+        firmware_version: env!("CARGO_PKG_VERSION"),
+        protocol_version: common::PROTOCOL_VERSION,
+        boot_counter: 0,
+    }
+}
+
 fn send_message(message: &Message, nrf: &mut nrf::TxMode<NrfType>)
     -> Result<(), Error>
 {
-    let bytes = postcard::to_vec::<consts::U128, _>(message)
+    let packet = Packet { station: STATION, message: message.clone() };
+    let bytes = postcard::to_vec::<consts::U128, _>(&packet)
         .map_err(Error::EncodingError)?;
+    if bytes.len() > common::MAX_PACKET_LENGTH {
+        return Err(Error::PacketTooLong(bytes.len()));
+    }
 
     nrf.send(&bytes)
         .map_err(Error::NrfTxError)?;
@@ -0,0 +1,181 @@
+// Load test of the server. Many stations send timestamped lines over TCP while
+// a client keeps reading one of the series over HTTP.
+//
+//     cargo bench --bench throughput
+//
+// starts a server in-process with its data in a temporary directory. Nothing
+// is logged, so the numbers do not include the cost of logging every line. To
+// measure a running server give its addresses instead. It has to answer every
+// line, which servers from before the OK/ERR replies do not
+//
+//     cargo bench --bench throughput -- [tcp address] [http address]
+//
+// Series are named bench_<connection> and can be removed afterwards with
+// `;reset:bench_<connection>`
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use structopt::StructOpt;
+
+const CONNECTIONS: usize = 64;
+const LINES_PER_CONNECTION: usize = 2000;
+// Timestamps far in the past so that the points do not mix with real data
+const FIRST_TIMESTAMP: u64 = 1_000_000_000;
+
+fn send_lines(address: &str, connection: usize) -> std::io::Result<()> {
+    let stream = TcpStream::connect(address)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    // Written from a separate thread so that the server never waits for us
+    let mut writer = stream;
+    let sender = thread::spawn(move || -> std::io::Result<()> {
+        for line in 0..LINES_PER_CONNECTION {
+            writeln!(
+                writer,
+                "bench_{} value={} {}",
+                connection,
+                line % 100,
+                FIRST_TIMESTAMP + line as u64
+            )?;
+        }
+        writer.flush()
+    });
+
+    let mut reply = String::new();
+    for _ in 0..LINES_PER_CONNECTION {
+        reply.clear();
+        if reader.read_line(&mut reply)? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if !reply.starts_with("OK") {
+            eprintln!("Unexpected reply {:?}", reply.trim());
+        }
+    }
+    sender.join().unwrap()
+}
+
+fn read_series(address: &str) -> std::io::Result<Duration> {
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(address)?;
+    write!(stream, "GET /data/bench_0 HTTP/1.0\r\nHost: {}\r\n\r\n", address)?;
+    stream.read_to_end(&mut vec!())?;
+    Ok(start.elapsed())
+}
+
+// A port that nothing listens on right now
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+// Starts a server with a config in `directory`. Returns the TCP and HTTP
+// addresses once it accepts connections
+fn start_server(directory: &Path) -> (String, String) {
+    let (tcp_port, http_port) = (free_port(), free_port());
+    fs::create_dir_all(directory).unwrap();
+    let config = directory.join("config.toml");
+    fs::write(&config, format!(
+        r#"
+        http_port = {http_port}
+        http_address = "127.0.0.1"
+        log_filename = "{directory}/data.json"
+        metadata_filename = "{directory}/metadata.json"
+        events_filename = "{directory}/events.json"
+        counters_filename = "{directory}/counters.json"
+
+        [[sources]]
+        kind = "tcp"
+        address = "127.0.0.1"
+        port = {tcp_port}
+        "#,
+        http_port = http_port,
+        tcp_port = tcp_port,
+        directory = directory.display()
+    )).unwrap();
+
+    let args = server::cli::Args::from_iter(&["server", "--config", config.to_str().unwrap()]);
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        if let Err(e) = runtime.block_on(server::run(args)) {
+            eprintln!("Server failed: {:?}", e);
+            std::process::exit(1);
+        }
+    });
+
+    let tcp_address = format!("127.0.0.1:{}", tcp_port);
+    let started = Instant::now();
+    while TcpStream::connect(&tcp_address).is_err() {
+        assert!(started.elapsed() < Duration::from_secs(10), "server did not start");
+        thread::sleep(Duration::from_millis(10));
+    }
+    (tcp_address, format!("127.0.0.1:{}", http_port))
+}
+
+fn percentile(sorted: &[Duration], percentile: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    sorted[(sorted.len() - 1) * percentile / 100]
+}
+
+fn main() {
+    // cargo bench passes --bench to the binary
+    let args = env::args().skip(1).filter(|arg| !arg.starts_with("--")).collect::<Vec<_>>();
+    let directory = env::temp_dir().join(format!("weather-bench-{}", std::process::id()));
+    let (tcp_address, http_address) = match args.as_slice() {
+        [tcp, http, ..] => (tcp.clone(), http.clone()),
+        _ => start_server(&directory),
+    };
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            let mut latencies = vec!();
+            while !done.load(Ordering::Relaxed) {
+                match read_series(&http_address) {
+                    Ok(latency) => latencies.push(latency),
+                    Err(e) => eprintln!("Failed to read series: {}", e),
+                }
+            }
+            latencies
+        })
+    };
+
+    let start = Instant::now();
+    let senders = (0..CONNECTIONS)
+        .map(|connection| {
+            let address = tcp_address.clone();
+            thread::spawn(move || send_lines(&address, connection))
+        })
+        .collect::<Vec<_>>();
+    for sender in senders {
+        if let Err(e) = sender.join().unwrap() {
+            eprintln!("Connection failed: {}", e);
+        }
+    }
+    let elapsed = start.elapsed();
+
+    done.store(true, Ordering::Relaxed);
+    let mut latencies = reader.join().unwrap();
+    latencies.sort();
+
+    let lines = CONNECTIONS * LINES_PER_CONNECTION;
+    println!("{} lines over {} connections in {:.2?}", lines, CONNECTIONS, elapsed);
+    println!("{:.0} lines/s", lines as f64 / elapsed.as_secs_f64());
+    println!(
+        "{} reads during ingestion, p50 {:.2?}, p99 {:.2?}",
+        latencies.len(),
+        percentile(&latencies, 50),
+        percentile(&latencies, 99)
+    );
+
+    let _ = fs::remove_dir_all(&directory);
+}
@@ -0,0 +1,425 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use thiserror::Error;
+
+use crate::config::{self, StationConfig};
+
+// Number of alert events kept around for the history endpoint
+const HISTORY_LENGTH: usize = 1000;
+
+#[derive(Error, Debug)]
+pub enum AlertError {
+    #[error("Alert {0} needs either a series or a station")]
+    NoTarget(String),
+    #[error("Alert {0} has both a series and a station")]
+    BothTargets(String),
+    #[error("Alert {0} is for a station, which only works with condition = \"no_data\"")]
+    StationCondition(String),
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Above,
+    Below,
+    NoData,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AlertRule {
+    pub name: String,
+    #[serde(default)]
+    pub series: String,
+    // Rules for a station look at all of its series instead of a single one
+    #[serde(default)]
+    pub station: Option<String>,
+    pub condition: Condition,
+    #[serde(default)]
+    pub threshold: f32,
+    // How long the condition has to hold before the alert triggers
+    #[serde(default)]
+    pub for_minutes: f64,
+    // How far back past the threshold the value has to go before an active
+    // alert is resolved
+    #[serde(default)]
+    pub hysteresis: f32,
+    // Names of the notifiers to send this alert to. All notifiers are used if empty
+    #[serde(default)]
+    pub notify: Vec<String>,
+}
+
+impl AlertRule {
+    fn check(&self) -> Result<(), AlertError> {
+        match (&self.station, self.series.is_empty()) {
+            (None, true) => Err(AlertError::NoTarget(self.name.clone())),
+            (Some(_), false) => Err(AlertError::BothTargets(self.name.clone())),
+            (Some(_), true) if self.condition != Condition::NoData =>
+                Err(AlertError::StationCondition(self.name.clone())),
+            _ => Ok(())
+        }
+    }
+
+    fn applies_to(&self, stations: &[StationConfig], name: &str) -> bool {
+        match &self.station {
+            Some(station) => config::split_series_name(stations, name).0 == Some(station.as_str()),
+            None => self.series == name,
+        }
+    }
+
+    fn is_violated(&self, value: f32, active: bool) -> bool {
+        let margin = if active { self.hysteresis } else { 0. };
+        match self.condition {
+            Condition::Above => value > self.threshold - margin,
+            Condition::Below => value < self.threshold + margin,
+            Condition::NoData => false,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.condition {
+            Condition::Above => format!("{} above {}", self.series, self.threshold),
+            Condition::Below => format!("{} below {}", self.series, self.threshold),
+            Condition::NoData => match &self.station {
+                Some(station) => format!("no data from station {}", station),
+                None => format!("no data for {}", self.series),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertEventKind {
+    Triggered,
+    Resolved,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AlertEvent {
+    pub timestamp: f64,
+    pub rule: String,
+    pub series: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub station: Option<String>,
+    pub kind: AlertEventKind,
+    pub value: Option<f32>,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct RuleState {
+    pending_since: Option<f64>,
+    active: bool,
+    last_seen: Option<f64>,
+}
+
+pub type SharedAlertEngine = Arc<Mutex<AlertEngine>>;
+
+pub fn check_rules(rules: &[AlertRule]) -> Result<(), AlertError> {
+    rules.iter().try_for_each(AlertRule::check)
+}
+
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    // Used to find the station of a series for station rules
+    stations: Vec<StationConfig>,
+    states: Vec<RuleState>,
+    history: VecDeque<AlertEvent>,
+    started: f64,
+    // Events are sent along with the names of the notifiers that should receive them
+    tx: Sender<(Vec<String>, AlertEvent)>,
+}
+
+impl AlertEngine {
+    pub fn new(
+        rules: Vec<AlertRule>,
+        stations: Vec<StationConfig>,
+        tx: Sender<(Vec<String>, AlertEvent)>
+    ) -> Self {
+        let states = rules.iter().map(|_| RuleState::default()).collect();
+        Self {
+            rules,
+            stations,
+            states,
+            history: VecDeque::new(),
+            started: Utc::now().timestamp() as f64,
+            tx,
+        }
+    }
+
+    // Rules that keep their name and target keep their state, so an active
+    // alert is not triggered again because its threshold changed
+    pub fn set_rules(&mut self, rules: Vec<AlertRule>) {
+        let now = Utc::now().timestamp() as f64;
+        let mut previous = self.rules.drain(..).zip(self.states.drain(..)).collect::<Vec<_>>();
+        self.states = rules.iter()
+            .map(|rule| {
+                previous.iter()
+                    .position(|(old, _)| {
+                        old.name == rule.name && old.series == rule.series && old.station == rule.station
+                    })
+                    .map(|index| previous.remove(index).1)
+                    // Added rules wait for data from now on, not since the start
+                    .unwrap_or_else(|| RuleState { last_seen: Some(now), ..RuleState::default() })
+            })
+            .collect();
+        self.rules = rules;
+    }
+
+    pub fn on_datapoint(&mut self, name: &str, value: f32, timestamp: f64) {
+        let mut events = vec!();
+        for (rule, state) in self.rules.iter().zip(self.states.iter_mut()) {
+            if !rule.applies_to(&self.stations, name) {
+                continue;
+            }
+            state.last_seen = Some(timestamp);
+
+            if rule.is_violated(value, state.active) {
+                let since = *state.pending_since.get_or_insert(timestamp);
+                if !state.active && timestamp - since >= rule.for_minutes * 60. {
+                    state.active = true;
+                    events.push((rule, AlertEventKind::Triggered));
+                }
+            }
+            else {
+                state.pending_since = None;
+                if state.active {
+                    state.active = false;
+                    events.push((rule, AlertEventKind::Resolved));
+                }
+            }
+        }
+
+        let events = events.into_iter()
+            .map(|(rule, kind)| (rule.notify.clone(), new_event(rule, kind, timestamp, Some(value))))
+            .collect::<Vec<_>>();
+        for event in events {
+            self.emit(event);
+        }
+    }
+
+    // Checks rules that trigger on the absence of data. This has to be called
+    // periodically since no datapoints arrive to trigger the check
+    pub fn check_no_data(&mut self, now: f64) {
+        let mut events = vec!();
+        for (rule, state) in self.rules.iter().zip(self.states.iter_mut()) {
+            if rule.condition != Condition::NoData || state.active {
+                continue;
+            }
+
+            let last_seen = state.last_seen.unwrap_or(self.started);
+            if now - last_seen >= rule.for_minutes * 60. {
+                state.active = true;
+                events.push((rule.notify.clone(), new_event(rule, AlertEventKind::Triggered, now, None)));
+            }
+        }
+
+        for event in events {
+            self.emit(event);
+        }
+    }
+
+    pub fn history(&self) -> Vec<&AlertEvent> {
+        self.history.iter().collect()
+    }
+
+    pub fn active(&self) -> Vec<&AlertRule> {
+        self.rules.iter()
+            .zip(self.states.iter())
+            .filter(|(_, state)| state.active)
+            .map(|(rule, _)| rule)
+            .collect()
+    }
+
+    fn emit(&mut self, (notify, event): (Vec<String>, AlertEvent)) {
+        info!("Alert: {}", event.message);
+
+        self.history.push_back(event.clone());
+        if self.history.len() > HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+
+        if let Err(e) = self.tx.send((notify, event)) {
+            error!("Failed to send alert to notifiers: {:?}", e);
+        }
+    }
+}
+
+fn new_event(rule: &AlertRule, kind: AlertEventKind, timestamp: f64, value: Option<f32>) -> AlertEvent {
+    let state = match kind {
+        AlertEventKind::Triggered => "triggered",
+        AlertEventKind::Resolved => "resolved",
+    };
+    let message = match value {
+        Some(value) => format!("{} {}: {} (value: {})", rule.name, state, rule.describe(), value),
+        None => format!("{} {}: {}", rule.name, state, rule.describe()),
+    };
+
+    AlertEvent {
+        timestamp,
+        rule: rule.name.clone(),
+        series: rule.series.clone(),
+        station: rule.station.clone(),
+        kind,
+        value,
+        message,
+    }
+}
+
+pub fn run_alert_timer(interval: Duration, engine: SharedAlertEngine) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            engine.lock().unwrap().check_no_data(Utc::now().timestamp() as f64);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::{channel, Receiver};
+
+    fn rule(condition: Condition, threshold: f32) -> AlertRule {
+        AlertRule {
+            name: "test".into(),
+            series: "temperature".into(),
+            station: None,
+            condition,
+            threshold,
+            for_minutes: 0.,
+            hysteresis: 0.,
+            notify: vec!(),
+        }
+    }
+
+    fn engine(rules: Vec<AlertRule>) -> (AlertEngine, Receiver<(Vec<String>, AlertEvent)>) {
+        let stations = vec!(StationConfig {
+            name: "garden".into(),
+            series: vec!("temperature".into()),
+            expected_interval: 300,
+            key: None,
+        });
+        let (tx, rx) = channel();
+        (AlertEngine::new(rules, stations, tx), rx)
+    }
+
+    fn events(rx: &Receiver<(Vec<String>, AlertEvent)>) -> Vec<(AlertEventKind, Option<f32>)> {
+        rx.try_iter().map(|(_, event)| (event.kind, event.value)).collect()
+    }
+
+    #[test]
+    fn hysteresis() {
+        let (mut engine, rx) = engine(vec!(AlertRule { hysteresis: 0.5, ..rule(Condition::Below, 0.) }));
+
+        engine.on_datapoint("temperature", -1., 0.);
+        assert_eq!(events(&rx), vec!((AlertEventKind::Triggered, Some(-1.))));
+        // Back above the threshold, but not by the hysteresis
+        engine.on_datapoint("temperature", 0.4, 60.);
+        assert_eq!(events(&rx), vec!());
+        assert_eq!(engine.active().len(), 1);
+        engine.on_datapoint("temperature", 0.6, 120.);
+        assert_eq!(events(&rx), vec!((AlertEventKind::Resolved, Some(0.6))));
+        // The hysteresis only applies to active alerts
+        engine.on_datapoint("temperature", -0.1, 180.);
+        assert_eq!(events(&rx), vec!((AlertEventKind::Triggered, Some(-0.1))));
+    }
+
+    #[test]
+    fn for_minutes() {
+        let (mut engine, rx) = engine(vec!(AlertRule { for_minutes: 15., ..rule(Condition::Above, 30.) }));
+
+        engine.on_datapoint("temperature", 31., 0.);
+        engine.on_datapoint("temperature", 32., 600.);
+        assert_eq!(events(&rx), vec!());
+        // A single value within the threshold starts the wait over
+        engine.on_datapoint("temperature", 29., 700.);
+        engine.on_datapoint("temperature", 31., 800.);
+        engine.on_datapoint("temperature", 31., 900.);
+        assert_eq!(events(&rx), vec!());
+        engine.on_datapoint("temperature", 33., 1700.);
+        assert_eq!(events(&rx), vec!((AlertEventKind::Triggered, Some(33.))));
+    }
+
+    #[test]
+    fn resolve() {
+        let (mut engine, rx) = engine(vec!(rule(Condition::Above, 30.)));
+
+        engine.on_datapoint("temperature", 31., 0.);
+        engine.on_datapoint("temperature", 32., 60.);
+        assert_eq!(events(&rx), vec!((AlertEventKind::Triggered, Some(31.))));
+        engine.on_datapoint("temperature", 20., 120.);
+        engine.on_datapoint("temperature", 19., 180.);
+        assert_eq!(events(&rx), vec!((AlertEventKind::Resolved, Some(20.))));
+        assert!(engine.active().is_empty());
+        assert_eq!(engine.history().len(), 2);
+    }
+
+    #[test]
+    fn other_series_are_ignored() {
+        let (mut engine, rx) = engine(vec!(rule(Condition::Above, 30.)));
+
+        engine.on_datapoint("humidity", 90., 0.);
+        engine.on_datapoint("roof.temperature", 40., 0.);
+        assert_eq!(events(&rx), vec!());
+    }
+
+    #[test]
+    fn silence() {
+        let (mut engine, rx) = engine(vec!(AlertRule { for_minutes: 30., ..rule(Condition::NoData, 0.) }));
+
+        engine.on_datapoint("temperature", 10., 1000.);
+        engine.check_no_data(1000. + 29. * 60.);
+        assert_eq!(events(&rx), vec!());
+        engine.check_no_data(1000. + 30. * 60.);
+        assert_eq!(events(&rx), vec!((AlertEventKind::Triggered, None)));
+        // Triggers once until data arrives again
+        engine.check_no_data(1000. + 60. * 60.);
+        assert_eq!(events(&rx), vec!());
+        engine.on_datapoint("temperature", 10., 5000.);
+        assert_eq!(events(&rx), vec!((AlertEventKind::Resolved, Some(10.))));
+    }
+
+    #[test]
+    fn station_silence() {
+        let station_rule = AlertRule {
+            series: String::new(),
+            station: Some("garden".into()),
+            for_minutes: 30.,
+            ..rule(Condition::NoData, 0.)
+        };
+        let (mut engine, rx) = engine(vec!(station_rule));
+
+        // Both series that the station lists and ones qualified with its name count
+        engine.on_datapoint("temperature", 10., 0.);
+        engine.on_datapoint("garden.wind", 3., 1000.);
+        engine.on_datapoint("roof.temperature", 10., 2000.);
+        engine.check_no_data(1000. + 29. * 60.);
+        assert_eq!(events(&rx), vec!());
+        engine.check_no_data(1000. + 30. * 60.);
+        let (_, event) = rx.try_recv().unwrap();
+        assert_eq!(event.kind, AlertEventKind::Triggered);
+        assert_eq!(event.station.as_deref(), Some("garden"));
+        assert_eq!(event.message, "test triggered: no data from station garden");
+    }
+
+    #[test]
+    fn rules_need_one_target() {
+        let station = |condition| AlertRule {
+            series: String::new(),
+            station: Some("garden".into()),
+            ..rule(condition, 0.)
+        };
+        assert!(check_rules(&[rule(Condition::Above, 0.), station(Condition::NoData)]).is_ok());
+        assert!(check_rules(&[station(Condition::Above)]).is_err());
+        assert!(check_rules(&[AlertRule { series: String::new(), ..rule(Condition::NoData, 0.) }]).is_err());
+        assert!(check_rules(&[AlertRule { station: Some("garden".into()), ..rule(Condition::NoData, 0.) }]).is_err());
+    }
+}
@@ -0,0 +1,43 @@
+use chrono::{DateTime, Utc};
+
+use crate::types::Datapoint;
+
+// A linear correction `raw * gain + offset` that applies to all values
+// measured after `from`
+#[derive(Deserialize, Clone, Debug)]
+pub struct Calibration {
+    pub from: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub offset: f32,
+    #[serde(default = "default_gain")]
+    pub gain: f32,
+}
+
+fn default_gain() -> f32 {
+    1.
+}
+
+impl Calibration {
+    fn starts_at(&self) -> f64 {
+        self.from.map_or(f64::NEG_INFINITY, |from| from.timestamp() as f64)
+    }
+}
+
+// Applies the newest calibration that was in effect at `timestamp`. Values
+// from before the first calibration are returned unchanged
+pub fn calibrate(calibrations: &[Calibration], value: f32, timestamp: f64) -> f32 {
+    calibrations.iter()
+        .filter(|calibration| calibration.starts_at() <= timestamp)
+        .max_by(|a, b| a.starts_at().partial_cmp(&b.starts_at()).unwrap())
+        .map_or(value, |calibration| value * calibration.gain + calibration.offset)
+}
+
+pub fn calibrate_series(calibrations: &[Calibration], data: &[Datapoint]) -> Vec<Datapoint> {
+    data.iter()
+        .map(|point| Datapoint {
+            timestamp: point.timestamp,
+            value: calibrate(calibrations, point.value, point.timestamp),
+            samples: point.samples,
+        })
+        .collect()
+}
@@ -0,0 +1,79 @@
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use crate::influx::Precision;
+use crate::simulator::Acceleration;
+
+#[derive(StructOpt)]
+#[structopt(about = "Collects readings from the weather stations and serves them")]
+pub struct Args {
+    #[structopt(short, long, default_value = "config.toml", parse(from_os_str))]
+    pub config: PathBuf,
+    /// Data file to use instead of log_filename from the config
+    #[structopt(short, long, parse(from_os_str))]
+    pub data: Option<PathBuf>,
+    /// One of off, error, warn, info, debug or trace. Overrides log_level from
+    /// the config, which defaults to info
+    #[structopt(short, long)]
+    pub log_level: Option<log::LevelFilter>,
+    /// Start a simulated station that writes fake readings. Use --data to keep
+    /// them apart from real data
+    #[structopt(long)]
+    pub demo: bool,
+    /// Seed of the simulated station, random if not set
+    #[structopt(long)]
+    pub seed: Option<u64>,
+    /// How much faster than real time the simulated station runs
+    #[structopt(long, default_value = "1")]
+    pub acceleration: Acceleration,
+    /// Check that the config is valid and exit
+    #[structopt(long)]
+    pub check_config: bool,
+    #[structopt(subcommand)]
+    pub command: Option<Maintenance>,
+}
+
+// Tasks that work on the stored data while the server is not running
+#[derive(StructOpt)]
+pub enum Maintenance {
+    /// List the stored series with their number of points and time range
+    List,
+    /// Rename a series along with its quarantine and metadata
+    Rename {
+        from: String,
+        to: String,
+    },
+    /// Delete the points of a series between two optional timestamps
+    Delete {
+        series: String,
+        #[structopt(long)]
+        from: Option<f64>,
+        #[structopt(long)]
+        to: Option<f64>,
+    },
+    /// Write all series as InfluxDB line protocol to stdout
+    Export {
+        #[structopt(long, default_value = "s")]
+        precision: Precision,
+        /// Export the stored values without calibration
+        #[structopt(long)]
+        raw: bool,
+    },
+    /// Read InfluxDB line protocol from a file. Values are stored without filtering
+    Import {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        #[structopt(long, default_value = "s")]
+        precision: Precision,
+    },
+    /// Write simulated history up to now into a new data file
+    Backfill {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        #[structopt(long, default_value = "7")]
+        days: u32,
+        #[structopt(long, default_value = "0")]
+        seed: u64,
+    },
+}
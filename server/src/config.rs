@@ -1,22 +1,143 @@
 use std::path::{PathBuf, Path};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 use crate::error::Result;
+use crate::alerts::{self, AlertRule};
+use crate::notifier::NotifierConfig;
+use crate::calibration::Calibration;
+use crate::units::Unit;
+use crate::source::SourceConfig;
+use crate::series::DuplicatePolicy;
+use crate::constants::STATION_SEPARATOR;
 
 use std::fs::File;
 use std::io::prelude::*;
 
-use color_anyhow::anyhow::Context;
+use color_anyhow::anyhow::{anyhow, Context};
 
 use toml;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct SeriesConfig {
+    // The unit values are stored in, required for conversion when reading
+    pub unit: Option<Unit>,
+    // Seconds between readings the source is expected to send
+    pub expected_interval: Option<u64>,
+    // Values outside min..max are rejected
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    // Largest allowed change per minute compared to the previous value
+    pub max_rate: Option<f32>,
+    // Values further than median_max_deviation from the median of the previous
+    // median_window values are rejected
+    pub median_window: Option<usize>,
+    pub median_max_deviation: Option<f32>,
+    // Stored values are raw, these are applied when the series is read
+    #[serde(default)]
+    pub calibration: Vec<Calibration>,
+    // Overrides the Home Assistant device class guessed from the unit and name
+    pub device_class: Option<String>,
+    // Applies to datapoints with the same timestamp as a stored one
+    #[serde(default)]
+    pub duplicates: DuplicatePolicy,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct StationConfig {
+    pub name: String,
+    pub series: Vec<String>,
+    pub expected_interval: u64,
+    // Shared key that the station signs its messages with. Series of the
+    // station can only be changed by authenticated messages if this is set
+    pub key: Option<String>,
+}
+
+impl StationConfig {
+    fn owns(&self, series: &str) -> bool {
+        self.series.iter().any(|name| name == series)
+    }
+}
+
+// Name of `series` sent by `station`. Series that a configured station lists are
+// stored under their plain name, others are qualified with the station name
+pub fn series_name(stations: &[StationConfig], station: &str, series: &str) -> String {
+    if stations.iter().any(|s| s.name == station && s.owns(series)) {
+        series.to_string()
+    }
+    else {
+        format!("{}{}{}", station, STATION_SEPARATOR, series)
+    }
+}
+
+// The inverse of series_name, returns the station and plain series name
+pub fn split_series_name<'a>(
+    stations: &'a [StationConfig],
+    name: &'a str
+) -> (Option<&'a str>, &'a str) {
+    if let Some(station) = stations.iter().find(|s| s.owns(name)) {
+        return (Some(&station.name), name);
+    }
+    let mut split = name.splitn(2, STATION_SEPARATOR);
+    match (split.next(), split.next()) {
+        (Some(station), Some(series)) => (Some(station), series),
+        _ => (None, name)
+    }
+}
+
+// Series settings can be changed while the server is running
+pub type SharedSeriesConfig = Arc<RwLock<HashMap<String, SeriesConfig>>>;
+
+#[derive(Deserialize, Clone)]
 pub struct Config {
     pub http_port: u16,
     pub http_address: String,
-    pub tcp_port: u16,
-    pub tcp_address: String,
+    // Where readings come from
+    #[serde(default)]
+    pub sources: Vec<SourceConfig>,
     pub log_filename: PathBuf,
+    #[serde(default = "default_metadata_filename")]
+    pub metadata_filename: PathBuf,
+    #[serde(default = "default_events_filename")]
+    pub events_filename: PathBuf,
+    // Replay counters of the stations with a key
+    #[serde(default = "default_counters_filename")]
+    pub counters_filename: PathBuf,
+    #[serde(default)]
+    pub alerts: Vec<AlertRule>,
+    #[serde(default)]
+    pub notifiers: Vec<NotifierConfig>,
+    #[serde(default)]
+    pub series: HashMap<String, SeriesConfig>,
+    #[serde(default)]
+    pub stations: Vec<StationConfig>,
+    // Seconds to wait for queued commands to be saved when shutting down
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    // Overridden by --log-level
+    pub log_level: Option<log::LevelFilter>,
 }
 
+fn default_metadata_filename() -> PathBuf {
+    "metadata.json".into()
+}
+
+fn default_events_filename() -> PathBuf {
+    "events.json".into()
+}
+
+fn default_counters_filename() -> PathBuf {
+    "counters.json".into()
+}
+
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+const MOVED_TO_SOURCES: [&str; 10] = [
+    "tcp_port", "tcp_address", "udp_port", "udp_address", "udp_dedup",
+    "postcard_port", "postcard_address", "mqtt", "serial", "nrf"
+];
+
 pub fn read_config(config_path: &Path) -> Result<Config> {
     let mut file = File::open(config_path)
         .with_context(|| format!("Failed to open {:?}", config_path))?;
@@ -24,5 +145,14 @@ pub fn read_config(config_path: &Path) -> Result<Config> {
     file.read_to_string(&mut content)
         .with_context(|| format!("Failed to read from {:?}", config_path))?;
 
-    Ok(toml::from_str(&content)?)
+    // These used to configure the sources, ignoring them would leave the
+    // server without its usual sources
+    let value = toml::from_str::<toml::Value>(&content)?;
+    if let Some(key) = MOVED_TO_SOURCES.iter().find(|key| value.get(**key).is_some()) {
+        return Err(anyhow!("{} is now configured in [[sources]], see config.toml in the repository", key));
+    }
+
+    let config: Config = toml::from_str(&content)?;
+    alerts::check_rules(&config.alerts)?;
+    Ok(config)
 }
@@ -1 +1,16 @@
 pub const OPERATION_PREFIX: char = ';';
+pub const SEQ_PREFIX: char = '#';
+pub const BATCH_SEPARATOR: char = ',';
+// Separates a line from its counter and MAC
+pub const AUTH_SEPARATOR: char = '|';
+
+// Appended to the name of a series to get the series where rejected values of
+// it are stored
+pub const QUARANTINE_SUFFIX: &str = ".quarantine";
+
+// Separates the station from the series in names of series that do not belong
+// to a configured station
+pub const STATION_SEPARATOR: char = '.';
+
+// Commands that can wait for the data handler before senders have to wait too
+pub const COMMAND_QUEUE_LENGTH: usize = 1024;
@@ -1,36 +1,236 @@
+use std::collections::HashMap;
 use std::thread;
-use std::sync::mpsc::Receiver;
 
 use chrono::{Utc};
 
-use crate::types::{ReadingCollection, Datapoint, Command};
+use tokio::sync::mpsc::Receiver;
 
-pub fn handle_datapoint((name,value,timestamp): (String, f32, Option<f64>), readings: &ReadingCollection) {
-    let timestamp = timestamp.unwrap_or(Utc::now().timestamp() as f64);
+use crate::types::{ReadingCollection, MetadataCollection, Datapoint, Command, Reply, Failure, AcceptedDatapoint, SharedSubscribers};
+use crate::alerts::SharedAlertEngine;
+use crate::health::SharedHealthTracker;
+use crate::config::{SeriesConfig, SharedSeriesConfig};
+use crate::filter;
+use crate::constants::BATCH_SEPARATOR;
+use crate::calibration;
+use crate::series::{self, Insertion};
+use crate::logger::{self, StorageFiles};
+use crate::events::{Event, Severity, SharedEventStore};
+use crate::protocol::SharedCounters;
 
-    let mut map = readings.lock().unwrap();
-    if !map.contains_key(&name) {
-        map.insert(name.clone(), vec!());
+// Everything that needs to know about incoming datapoints
+#[derive(Clone)]
+pub struct IngestState {
+    pub readings: ReadingCollection,
+    pub alerts: SharedAlertEngine,
+    pub health: SharedHealthTracker,
+    pub series: SharedSeriesConfig,
+    pub subscribers: SharedSubscribers,
+    pub metadata: MetadataCollection,
+    pub events: SharedEventStore,
+    pub counters: SharedCounters,
+    pub files: StorageFiles,
+}
+
+// The calibrated values that the filter compares a new value with. Backfilled
+// values are compared to the values that came before them
+fn recent(
+    map: &HashMap<String, Vec<Datapoint>>,
+    name: &str,
+    config: &SeriesConfig,
+    timestamp: f64
+) -> Vec<Datapoint> {
+    let data = map.get(name).map(|data| data.as_slice()).unwrap_or(&[]);
+    let end = match series::position(data, timestamp) {
+        Ok(index) | Err(index) => index
+    };
+    let recent = &data[end.saturating_sub(filter::history_length(config))..end];
+    calibration::calibrate_series(&config.calibration, recent)
+}
+
+pub fn handle_datapoint(
+    (name,value,timestamp): (String, f32, Option<f64>),
+    state: &IngestState
+) -> Result<(), filter::Rejection> {
+    let now = Utc::now().timestamp() as f64;
+    let timestamp = timestamp.unwrap_or(now);
+
+    // Even rejected values show that the sensor is alive
+    state.health.lock().unwrap().on_datapoint(&name, now);
+
+    let series_config = state.series.read().unwrap();
+    let mut map = state.readings.write().unwrap();
+    let policy = series_config.get(&name).map(|config| config.duplicates).unwrap_or_default();
+
+    // Raw values are stored while filtering and alerts work on calibrated values
+    let mut calibrated = value;
+    if let Some(config) = series_config.get(&name) {
+        calibrated = calibration::calibrate(&config.calibration, value, timestamp);
+
+        let history = recent(&map, &name, config, timestamp);
+        let quarantined = recent(&map, &filter::quarantine_name(&name), config, timestamp);
+        if let Err(rejection) = filter::check(config, &history, &quarantined, calibrated, timestamp) {
+            warn!("Rejected {} value: {}", name, rejection);
+            series::insert(
+                map.entry(filter::quarantine_name(&name)).or_default(),
+                Datapoint::new(timestamp, value),
+                policy
+            );
+            return Err(rejection);
+        }
+    }
+
+    let data = map.entry(name.clone()).or_default();
+    if series::insert(data, Datapoint::new(timestamp, value), policy) == Insertion::Ignored {
+        debug!("Ignoring duplicate {} value at {}", name, timestamp);
+        return Ok(());
     }
-    map.get_mut(&name).unwrap().push(Datapoint{timestamp, value});
+
+    state.alerts.lock().unwrap().on_datapoint(&name, calibrated, timestamp);
+
+    let accepted = AcceptedDatapoint {
+        name: name.clone(),
+        raw: value,
+        value: calibrated,
+    };
+    // Subscribers that are gone have been stopped
+    state.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(accepted.clone()).is_ok());
+    Ok(())
 }
 
-pub fn run_command_handler(rx: Receiver<Command>, readings: ReadingCollection) {
-    thread::spawn(move || {
-        loop {
-            let command = rx.recv().unwrap();
-
-            match command {
-                Command::Reset(name) => {
-                    let mut map = readings.lock().unwrap();
-                    if map.contains_key(&name) {
-                        map.remove(&name);
-                    }
+pub fn rename(
+    readings: &ReadingCollection,
+    metadata: &MetadataCollection,
+    from: &str,
+    to: &str
+) -> Reply {
+    let mut map = readings.write().unwrap();
+    if map.contains_key(to) {
+        return Err(format!("{} already exists", to).into());
+    }
+    let data = map.remove(from).ok_or_else(|| format!("No series named {}", from))?;
+    map.insert(to.to_string(), data);
+    if let Some(quarantined) = map.remove(&filter::quarantine_name(from)) {
+        map.insert(filter::quarantine_name(to), quarantined);
+    }
+
+    let mut metadata = metadata.lock().unwrap();
+    if let Some(entries) = metadata.remove(from) {
+        metadata.insert(to.to_string(), entries);
+    }
+    Ok(None)
+}
+
+pub fn delete_range(readings: &ReadingCollection, name: &str, from: Option<f64>, to: Option<f64>) -> Reply {
+    let mut map = readings.write().unwrap();
+    let data = map.get_mut(name).ok_or_else(|| format!("No series named {}", name))?;
+
+    let before = data.len();
+    data.retain(|point| {
+        point.timestamp < from.unwrap_or(f64::NEG_INFINITY)
+            || point.timestamp > to.unwrap_or(f64::INFINITY)
+    });
+    Ok(Some((before - data.len()).to_string()))
+}
+
+fn handle_command(command: Command, state: &IngestState) -> Reply {
+    match command {
+        Command::Reset(name) => {
+            let mut map = state.readings.write().unwrap();
+            map.remove(&name);
+            // The quarantined values would otherwise still be compared with
+            // new values by the median filter
+            map.remove(&filter::quarantine_name(&name));
+            Ok(None)
+        }
+        Command::AddDatapoint(name, value, timestamp) => {
+            handle_datapoint((name, value, timestamp), state)
+                .map(|_| None)
+                .map_err(|rejection| Failure::Rejected(rejection.to_string()))
+        }
+        Command::Rename(from, to) => rename(&state.readings, &state.metadata, &from, &to),
+        Command::DeleteRange(name, from, to) => delete_range(&state.readings, &name, from, to),
+        Command::ListSeries => {
+            let map = state.readings.read().unwrap();
+            let mut names = map.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+            Ok(Some(names.join(&BATCH_SEPARATOR.to_string())))
+        }
+        Command::Snapshot => {
+            logger::snapshot(&state.files, &state.readings, &state.metadata, &state.events, &state.counters)
+                .map(|_| None)
+                .map_err(|e| Failure::Error(e.to_string()))
+        }
+        Command::SetMetadata(name, key, value) => {
+            let mut metadata = state.metadata.lock().unwrap();
+            let entries = metadata.entry(name.clone()).or_default();
+            if value.is_empty() {
+                entries.remove(&key);
+                if entries.is_empty() {
+                    metadata.remove(&name);
                 }
-                Command::AddDatapoint(name, value, timestamp) => {
-                    handle_datapoint((name, value, timestamp), &readings)
+            }
+            else {
+                entries.insert(key, value);
+            }
+            Ok(None)
+        }
+        Command::Time => {
+            let now = Utc::now();
+            Ok(Some(format!("{}.{:03}", now.timestamp(), now.timestamp_subsec_millis())))
+        }
+        Command::Event(event) => {
+            let station = event.station.as_deref().unwrap_or("unknown station");
+            match event.severity {
+                Severity::Info => info!("{} reported: {}", station, event.message),
+                Severity::Warning => warn!("{} reported: {}", station, event.message),
+                Severity::Error | Severity::Fatal => error!("{} reported: {}", station, event.message),
+            }
+            state.events.lock().unwrap().push(event);
+            Ok(None)
+        }
+        Command::Hello(identity) => {
+            let now = Utc::now().timestamp() as f64;
+            let previous = state.health.lock().unwrap().on_hello(identity.clone(), now);
+            // A new boot counter means that the station has restarted since it last said hello
+            if previous.filter(|previous| previous.boot_counter != identity.boot_counter).is_some() {
+                let mut event = Event::new(
+                    Severity::Info,
+                    &format!("Booted with firmware {} (boot {})", identity.firmware_version, identity.boot_counter)
+                );
+                event.station = Some(identity.station);
+                state.events.lock().unwrap().push(event);
+            }
+            Ok(None)
+        }
+        // Only the command loop can stop itself
+        Command::Shutdown(_) => Err("Shutdown can not be sent with a reply".into()),
+        Command::WithReply(command, reply) => {
+            let result = handle_command(*command, state);
+            // The sender is gone if the connection was closed, nothing to do about that
+            let _ = reply.send(result.clone());
+            result
+        }
+    }
+}
+
+// Runs on its own thread since commands only take locks and sometimes write files
+pub fn run_command_handler(mut rx: Receiver<Command>, state: IngestState) {
+    thread::spawn(move || {
+        while let Some(command) = rx.blocking_recv() {
+            if let Command::Shutdown(done) = command {
+                // Nothing can be queued after this, but what already is gets handled
+                rx.close();
+                while let Some(command) = rx.blocking_recv() {
+                    let _ = handle_command(command, &state);
                 }
+                let _ = done.send(handle_command(Command::Snapshot, &state));
+                info!("Command handler stopped");
+                return;
             }
+
+            // Failures are logged where they happen, and reported back to
+            // the sender for commands that want a reply
+            let _ = handle_command(command, &state);
         }
     });
 }
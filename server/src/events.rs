@@ -0,0 +1,133 @@
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+
+use thiserror::Error;
+
+// Number of events kept around. The counts cover all events ever received
+const EVENT_LOG_LENGTH: usize = 5000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+    Fatal,
+}
+
+#[derive(Error, Debug)]
+pub enum EventError {
+    #[error("Unknown severity {0}")]
+    UnknownSeverity(String),
+}
+
+impl FromStr for Severity {
+    type Err = EventError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "info" => Severity::Info,
+            "warning" => Severity::Warning,
+            "error" => Severity::Error,
+            "fatal" => Severity::Fatal,
+            other => return Err(EventError::UnknownSeverity(other.to_string()))
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Event {
+    pub timestamp: f64,
+    // Filled in by whatever received the event if the station did not say who it is
+    pub station: Option<String>,
+    pub severity: Severity,
+    pub kind: String,
+    pub message: String,
+}
+
+impl Event {
+    pub fn new(severity: Severity, message: &str) -> Self {
+        Self {
+            timestamp: Utc::now().timestamp() as f64,
+            station: None,
+            severity,
+            kind: error_kind(message),
+            message: message.to_string(),
+        }
+    }
+}
+
+// The stations send the Debug output of their error enums, so the kind is
+// the name of the outermost variant, `Esp` in `Esp(TimedOut)`
+fn error_kind(message: &str) -> String {
+    let kind = message.trim()
+        .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':'))
+        .next()
+        .unwrap_or("")
+        .trim_end_matches(':');
+    if kind.is_empty() { "unknown".into() } else { kind.to_string() }
+}
+
+#[derive(Default)]
+pub struct EventQuery {
+    pub station: Option<String>,
+    pub kind: Option<String>,
+    // Only events at least this severe
+    pub severity: Option<Severity>,
+    pub since: Option<f64>,
+    // Only the newest events
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct EventStore {
+    events: VecDeque<Event>,
+    counts: HashMap<String, u64>,
+}
+
+pub type SharedEventStore = Arc<Mutex<EventStore>>;
+
+impl EventStore {
+    pub fn push(&mut self, event: Event) {
+        *self.counts.entry(event.kind.clone()).or_insert(0) += 1;
+        self.events.push_back(event);
+        if self.events.len() > EVENT_LOG_LENGTH {
+            self.events.pop_front();
+        }
+    }
+
+    pub fn query(&self, query: &EventQuery) -> Vec<&Event> {
+        let matching = self.events.iter()
+            .filter(|event| query.station.is_none() || event.station == query.station)
+            .filter(|event| query.kind.is_none() || query.kind.as_ref() == Some(&event.kind))
+            .filter(|event| query.severity.is_none() || Some(event.severity) >= query.severity)
+            .filter(|event| query.since.is_none() || Some(event.timestamp) >= query.since)
+            .collect::<Vec<_>>();
+
+        let skip = query.limit.map(|limit| matching.len().saturating_sub(limit)).unwrap_or(0);
+        matching.into_iter().skip(skip).collect()
+    }
+
+    pub fn counts(&self) -> &HashMap<String, u64> {
+        &self.counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds() {
+        assert_eq!(error_kind("Esp(TimedOut)"), "Esp");
+        assert_eq!(error_kind("  Bme280 { address: 118 }"), "Bme280");
+        assert_eq!(error_kind("Spi::Overrun"), "Spi::Overrun");
+        assert_eq!(error_kind("NoAck: retrying"), "NoAck");
+        assert_eq!(error_kind("sensor_fault"), "sensor_fault");
+        assert_eq!(error_kind(""), "unknown");
+        assert_eq!(error_kind("(broken)"), "unknown");
+    }
+}
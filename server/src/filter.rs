@@ -0,0 +1,165 @@
+use std::cmp::Ordering;
+
+use thiserror::Error;
+
+use crate::config::SeriesConfig;
+use crate::types::Datapoint;
+use crate::constants::QUARANTINE_SUFFIX;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum Rejection {
+    #[error("{0} is outside the valid range {1}..{2}")]
+    OutOfRange(f32, f32, f32),
+    #[error("{0} changed by {1}/min from the previous value, more than the allowed {2}/min")]
+    RateOfChange(f32, f32, f32),
+    #[error("{0} deviates more than {2} from the median {1} of the previous values")]
+    MedianDeviation(f32, f32, f32),
+}
+
+pub fn quarantine_name(name: &str) -> String {
+    format!("{}{}", name, QUARANTINE_SUFFIX)
+}
+
+// Number of previous values that `check` needs to see
+pub fn history_length(config: &SeriesConfig) -> usize {
+    config.median_window.unwrap_or(0).max(1)
+}
+
+// The values that the median of a new value is taken from. Quarantined values
+// are included so that a lasting change, like a sensor that was moved, gets
+// accepted once most of the window agrees with it
+fn median_values(history: &[Datapoint], quarantined: &[Datapoint], window: usize) -> Vec<f32> {
+    let mut points = history.iter().chain(quarantined).collect::<Vec<_>>();
+    points.sort_by(|a, b| a.timestamp.partial_cmp(&b.timestamp).unwrap_or(Ordering::Equal));
+    points[points.len().saturating_sub(window)..].iter()
+        .map(|point| point.value)
+        .collect()
+}
+
+// Checks a new value against the validity rules of its series. `history` is the
+// previously accepted data of the series and `quarantined` the previously
+// rejected data, both from before `timestamp`
+pub fn check(
+    config: &SeriesConfig,
+    history: &[Datapoint],
+    quarantined: &[Datapoint],
+    value: f32,
+    timestamp: f64
+) -> Result<(), Rejection> {
+    let min = config.min.unwrap_or(f32::NEG_INFINITY);
+    let max = config.max.unwrap_or(f32::INFINITY);
+    if value < min || value > max || value.is_nan() {
+        return Err(Rejection::OutOfRange(value, min, max));
+    }
+
+    if let (Some(max_rate), Some(previous)) = (config.max_rate, history.last()) {
+        let minutes = (timestamp - previous.timestamp) / 60.;
+        if minutes > 0. {
+            let rate = ((value - previous.value).abs() as f64 / minutes) as f32;
+            if rate > max_rate {
+                return Err(Rejection::RateOfChange(value, rate, max_rate));
+            }
+        }
+    }
+
+    if let (Some(window), Some(max_deviation)) = (config.median_window, config.median_max_deviation) {
+        if window > 0 && history.len() >= window {
+            let mut values = median_values(history, quarantined, window);
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            let median = if window % 2 == 0 {
+                (values[window / 2 - 1] + values[window / 2]) / 2.
+            }
+            else {
+                values[window / 2]
+            };
+
+            if (value - median).abs() > max_deviation {
+                return Err(Rejection::MedianDeviation(value, median, max_deviation));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(values: &[f32]) -> Vec<Datapoint> {
+        values.iter()
+            .enumerate()
+            .map(|(i, value)| Datapoint::new(i as f64 * 60., *value))
+            .collect()
+    }
+
+    fn median_config() -> SeriesConfig {
+        SeriesConfig {
+            median_window: Some(5),
+            median_max_deviation: Some(2.),
+            ..SeriesConfig::default()
+        }
+    }
+
+    #[test]
+    fn range() {
+        let config = SeriesConfig { min: Some(-10.), max: Some(10.), ..SeriesConfig::default() };
+        assert_eq!(check(&config, &[], &[], 5., 0.), Ok(()));
+        assert_eq!(check(&config, &[], &[], 10., 0.), Ok(()));
+        assert_eq!(check(&config, &[], &[], 11., 0.), Err(Rejection::OutOfRange(11., -10., 10.)));
+        assert_eq!(check(&config, &[], &[], -11., 0.), Err(Rejection::OutOfRange(-11., -10., 10.)));
+        assert!(check(&SeriesConfig::default(), &[], &[], f32::NAN, 0.).is_err());
+    }
+
+    #[test]
+    fn rate_of_change() {
+        let config = SeriesConfig { max_rate: Some(1.), ..SeriesConfig::default() };
+        let history = points(&[0., 1.]);
+        assert_eq!(check(&config, &history, &[], 2., 120.), Ok(()));
+        assert_eq!(check(&config, &history, &[], 4., 120.), Err(Rejection::RateOfChange(4., 3., 1.)));
+        // Slower changes are fine after a longer gap
+        assert_eq!(check(&config, &history, &[], 4., 240.), Ok(()));
+        // Nothing to compare with
+        assert_eq!(check(&config, &[], &[], 100., 0.), Ok(()));
+    }
+
+    #[test]
+    fn median_deviation() {
+        let config = median_config();
+        let history = points(&[10., 11., 30., 10., 9.]);
+        assert_eq!(check(&config, &history, &[], 11., 300.), Ok(()));
+        assert_eq!(check(&config, &history, &[], 20., 300.), Err(Rejection::MedianDeviation(20., 10., 2.)));
+        // Too little history to tell
+        assert_eq!(check(&config, &history[..4], &[], 20., 240.), Ok(()));
+    }
+
+    #[test]
+    fn median_of_even_window() {
+        let config = SeriesConfig { median_window: Some(4), ..median_config() };
+        let history = points(&[10., 12., 14., 16.]);
+        assert_eq!(check(&config, &history, &[], 15., 240.), Ok(()));
+        assert_eq!(check(&config, &history, &[], 16., 240.), Err(Rejection::MedianDeviation(16., 13., 2.)));
+    }
+
+    #[test]
+    fn single_spikes_stay_quarantined() {
+        let config = median_config();
+        let history = points(&[10., 10., 10., 10., 10.]);
+        let quarantined = vec!(Datapoint::new(290., 50.));
+        assert!(check(&config, &history, &quarantined, 50., 300.).is_err());
+    }
+
+    #[test]
+    fn step_change_is_accepted_eventually() {
+        let config = median_config();
+        let history = points(&[10., 10., 10., 10., 10.]);
+        let mut quarantined = vec!();
+        let mut timestamp = 300.;
+        while check(&config, &history, &quarantined, 20., timestamp).is_err() {
+            quarantined.push(Datapoint::new(timestamp, 20.));
+            timestamp += 60.;
+            assert!(quarantined.len() <= 3, "the new level was never accepted");
+        }
+        assert_eq!(quarantined.len(), 3);
+    }
+}
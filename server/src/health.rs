@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::config::{SeriesConfig, StationConfig};
+use crate::types::ReadingCollection;
+use crate::identity::Identity;
+use crate::source::SourceStatus;
+
+// A source is late once it has been silent for this many expected intervals,
+// and offline after OFFLINE_FACTOR intervals
+const LATE_FACTOR: f64 = 1.5;
+pub const OFFLINE_FACTOR: f64 = 3.;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Online,
+    Late,
+    Offline,
+    // No expected interval is configured, or nothing has been heard yet
+    Unknown,
+}
+
+impl Status {
+    fn from_age(age: Option<f64>, expected_interval: Option<u64>) -> Self {
+        match (age, expected_interval) {
+            (Some(age), Some(interval)) => {
+                let interval = interval as f64;
+                if age <= interval * LATE_FACTOR {
+                    Status::Online
+                }
+                else if age <= interval * OFFLINE_FACTOR {
+                    Status::Late
+                }
+                else {
+                    Status::Offline
+                }
+            }
+            (None, Some(_)) => Status::Offline,
+            _ => Status::Unknown
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct HealthEntry {
+    pub status: Status,
+    pub last_seen: Option<f64>,
+    pub expected_interval: Option<u64>,
+    // What the station announced in its last hello, if it has sent one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<Identity>,
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    pub stations: HashMap<String, HealthEntry>,
+    pub series: HashMap<String, HealthEntry>,
+    // Filled in by the web server which owns the sources
+    pub sources: HashMap<String, SourceStatus>,
+}
+
+pub type SharedHealthTracker = Arc<Mutex<HealthTracker>>;
+
+pub struct HealthTracker {
+    stations: Vec<StationConfig>,
+    series: HashMap<String, SeriesConfig>,
+    last_seen: HashMap<String, f64>,
+    // The last hello of each station and when it was received
+    identities: HashMap<String, (Identity, f64)>,
+    // Last reported status of each station and series, used to log transitions
+    statuses: HashMap<String, Status>,
+}
+
+impl HealthTracker {
+    pub fn new(
+        stations: Vec<StationConfig>,
+        series: HashMap<String, SeriesConfig>,
+        readings: &ReadingCollection
+    ) -> Self {
+        // Start out from the newest stored datapoints so a restart does not
+        // make everything look fresh
+        let last_seen = readings.read().unwrap()
+            .iter()
+            .filter_map(|(name, data)| data.last().map(|point| (name.clone(), point.timestamp)))
+            .collect();
+
+        Self {
+            stations,
+            series,
+            last_seen,
+            identities: HashMap::new(),
+            statuses: HashMap::new(),
+        }
+    }
+
+    pub fn set_series(&mut self, series: HashMap<String, SeriesConfig>) {
+        self.series = series;
+    }
+
+    pub fn on_datapoint(&mut self, name: &str, received: f64) {
+        self.last_seen.insert(name.to_string(), received);
+    }
+
+    // Returns the previous identity of the station
+    pub fn on_hello(&mut self, identity: Identity, received: f64) -> Option<Identity> {
+        self.identities.insert(identity.station.clone(), (identity, received))
+            .map(|(previous, _)| previous)
+    }
+
+    fn station_last_seen(&self, station: &StationConfig) -> Option<f64> {
+        let hello = self.identities.get(&station.name).map(|(_, received)| received);
+        station.series.iter()
+            .filter_map(|name| self.last_seen.get(name))
+            .chain(hello)
+            .cloned()
+            .max_by(|a, b| a.partial_cmp(b).unwrap())
+    }
+
+    pub fn report(&self, now: f64) -> HealthReport {
+        let entry = |last_seen: Option<f64>, expected_interval: Option<u64>| HealthEntry {
+            status: Status::from_age(last_seen.map(|time| now - time), expected_interval),
+            last_seen,
+            expected_interval,
+            identity: None,
+        };
+
+        let mut stations = self.stations.iter()
+            .map(|station| {
+                (station.name.clone(), entry(self.station_last_seen(station), Some(station.expected_interval)))
+            })
+            .collect::<HashMap<_, _>>();
+        // Stations that are not configured show up once they say hello
+        for (name, (identity, received)) in &self.identities {
+            stations.entry(name.clone())
+                .or_insert_with(|| entry(Some(*received), None))
+                .identity = Some(identity.clone());
+        }
+
+        let mut series = self.last_seen.iter()
+            .map(|(name, last_seen)| {
+                let interval = self.series.get(name).and_then(|s| s.expected_interval);
+                (name.clone(), entry(Some(*last_seen), interval))
+            })
+            .collect::<HashMap<_, _>>();
+        // Configured series that have never reported
+        for (name, config) in &self.series {
+            series.entry(name.clone())
+                .or_insert_with(|| entry(None, config.expected_interval));
+        }
+
+        HealthReport { stations, series, sources: HashMap::new() }
+    }
+
+    // Logs every station and series whose status changed since the last check
+    pub fn check_transitions(&mut self, now: f64) {
+        let report = self.report(now);
+
+        let stations = report.stations.iter().map(|(name, e)| (format!("Station {}", name), e.status));
+        let series = report.series.iter().map(|(name, e)| (format!("Series {}", name), e.status));
+
+        for (name, status) in stations.chain(series) {
+            let previous = self.statuses.insert(name.clone(), status);
+            if previous == Some(status) || (previous.is_none() && status == Status::Online) {
+                continue;
+            }
+            match status {
+                Status::Online => info!("{} is back online", name),
+                Status::Late => warn!("{} is late", name),
+                Status::Offline => warn!("{} is offline", name),
+                Status::Unknown => {}
+            }
+        }
+    }
+}
+
+pub fn run_health_checker(interval: Duration, tracker: SharedHealthTracker) {
+    thread::spawn(move || {
+        loop {
+            tracker.lock().unwrap().check_transitions(Utc::now().timestamp() as f64);
+            thread::sleep(interval);
+        }
+    });
+}
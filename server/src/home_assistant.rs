@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use crate::config::{self, SeriesConfig, StationConfig};
+use crate::constants::QUARANTINE_SUFFIX;
+use crate::health::OFFLINE_FACTOR;
+use crate::mqtt::MqttConfig;
+use crate::units::Quantity;
+
+#[derive(Serialize)]
+struct Device {
+    identifiers: Vec<String>,
+    name: String,
+    manufacturer: &'static str,
+    model: &'static str,
+}
+
+#[derive(Serialize)]
+struct SensorConfig {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<String>,
+    state_class: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expire_after: Option<u64>,
+    device: Device,
+}
+
+fn object_id(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+// Guesses the Home Assistant device class from the unit of the series
+fn device_class(series: &str, config: Option<&SeriesConfig>) -> Option<String> {
+    if let Some(class) = config.and_then(|c| c.device_class.clone()) {
+        return Some(class);
+    }
+
+    // Home Assistant requires a unit for all of these classes
+    let class = match config.and_then(|c| c.unit).map(|unit| unit.quantity()) {
+        Some(Quantity::Humidity) if series.contains("battery") => "battery",
+        Some(Quantity::Humidity) => "humidity",
+        Some(Quantity::Voltage) => "voltage",
+        Some(Quantity::Temperature) => "temperature",
+        Some(Quantity::Pressure) => "atmospheric_pressure",
+        Some(Quantity::Speed) => "wind_speed",
+        Some(Quantity::Length) => "precipitation",
+        None => return None
+    };
+    Some(class.to_string())
+}
+
+// The discovery topic and payload that makes Home Assistant pick up `name`
+pub fn discovery_message(
+    mqtt: &MqttConfig,
+    stations: &[StationConfig],
+    series: &HashMap<String, SeriesConfig>,
+    name: &str
+) -> Option<(String, String)> {
+    if name.ends_with(QUARANTINE_SUFFIX) {
+        return None;
+    }
+
+    let (station, plain_name) = config::split_series_name(stations, name);
+    let station = station.unwrap_or(&mqtt.default_station);
+    let series_config = series.get(name);
+
+    let unique_id = format!("{}_{}_{}", object_id(&mqtt.topic_prefix), object_id(station), object_id(plain_name));
+    let payload = SensorConfig {
+        name: format!("{} {}", station, plain_name.replace('_', " ")),
+        unique_id: unique_id.clone(),
+        state_topic: format!("{}/state", mqtt.series_topic(stations, name)),
+        unit_of_measurement: series_config.and_then(|c| c.unit).map(|unit| unit.symbol()),
+        device_class: device_class(plain_name, series_config),
+        state_class: "measurement",
+        // Matches when the health check considers the series offline
+        expire_after: series_config
+            .and_then(|c| c.expected_interval)
+            .map(|interval| (interval as f64 * OFFLINE_FACTOR).ceil() as u64),
+        device: Device {
+            identifiers: vec!(format!("{}_{}", object_id(&mqtt.topic_prefix), object_id(station))),
+            name: station.to_string(),
+            manufacturer: "TheZoq2",
+            model: "weather station",
+        },
+    };
+
+    let topic = format!("{}/sensor/{}/config", mqtt.discovery_prefix, unique_id);
+    serde_json::to_string(&payload).ok().map(|payload| (topic, payload))
+}
@@ -0,0 +1,47 @@
+use thiserror::Error;
+
+use common::{Header, PROTOCOL_VERSION};
+
+// Oldest protocol version that the server still understands
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+#[derive(Error, Debug, Clone)]
+pub enum IdentityError {
+    #[error("Protocol version {0} is not supported, expected {1}..={2}")]
+    IncompatibleProtocol(u8, u8, u8),
+}
+
+// What a station announces about itself in its hello
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub station: String,
+    pub firmware_version: String,
+    pub protocol_version: u8,
+    pub boot_counter: u32,
+}
+
+impl Identity {
+    pub fn check_protocol(&self) -> Result<(), IdentityError> {
+        if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.protocol_version) {
+            Ok(())
+        }
+        else {
+            Err(IdentityError::IncompatibleProtocol(
+                self.protocol_version,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION
+            ))
+        }
+    }
+}
+
+impl<'a> From<&Header<'a>> for Identity {
+    fn from(header: &Header<'a>) -> Self {
+        Self {
+            station: header.station.to_string(),
+            firmware_version: header.firmware_version.to_string(),
+            protocol_version: header.protocol_version,
+            boot_counter: header.boot_counter,
+        }
+    }
+}
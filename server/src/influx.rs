@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::config::{self, SeriesConfig, StationConfig};
+use crate::calibration;
+use crate::constants::QUARANTINE_SUFFIX;
+use crate::types::{Command, Datapoint};
+
+// Tag that holds the name of the station a line was sent by
+const STATION_TAG: &str = "station";
+// Field used for measurements that only have a single value
+const VALUE_FIELD: &str = "value";
+
+#[derive(Error, Debug, Clone)]
+pub enum InfluxError {
+    #[error("Missing fields in {0:?}")]
+    MissingFields(String),
+    #[error("Invalid key=value pair {0:?}")]
+    InvalidPair(String),
+    #[error("No numeric fields in {0:?}")]
+    NoNumericFields(String),
+    #[error("Failed to parse field value {0:?}")]
+    InvalidValue(String),
+    #[error("Failed to parse timestamp {0:?}")]
+    InvalidTimestamp(String),
+    #[error("Unknown precision {0:?}")]
+    UnknownPrecision(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+    // Guessed from the magnitude of the timestamp, which works for any
+    // timestamp after 1973
+    Auto,
+}
+
+impl Precision {
+    fn seconds(self, timestamp: i64) -> f64 {
+        let divisor = match self {
+            Precision::Nanoseconds => 1e9,
+            Precision::Microseconds => 1e6,
+            Precision::Milliseconds => 1e3,
+            Precision::Seconds => 1.,
+            Precision::Auto => {
+                match timestamp.abs() {
+                    t if t >= 100_000_000_000_000_000 => 1e9,
+                    t if t >= 100_000_000_000_000 => 1e6,
+                    t if t >= 100_000_000_000 => 1e3,
+                    _ => 1.
+                }
+            }
+        };
+        timestamp as f64 / divisor
+    }
+
+    fn timestamp(self, seconds: f64) -> i64 {
+        let multiplier = match self {
+            Precision::Nanoseconds => 1e9,
+            Precision::Microseconds => 1e6,
+            Precision::Milliseconds => 1e3,
+            Precision::Seconds | Precision::Auto => 1.,
+        };
+        (seconds * multiplier).round() as i64
+    }
+}
+
+impl FromStr for Precision {
+    type Err = InfluxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "ns" | "n" => Precision::Nanoseconds,
+            "us" | "u" => Precision::Microseconds,
+            "ms" => Precision::Milliseconds,
+            "s" => Precision::Seconds,
+            other => return Err(InfluxError::UnknownPrecision(other.to_string()))
+        })
+    }
+}
+
+// Splits on `separator` where it is not escaped by a backslash or inside a quoted string
+fn split_escaped(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec!();
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut chars = s.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => { chars.next(); }
+            '"' => in_quotes = !in_quotes,
+            c if c == separator && !in_quotes => {
+                parts.push(&s[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            c => result.push(c)
+        }
+    }
+    result
+}
+
+fn split_pair(pair: &str) -> Result<(String, &str), InfluxError> {
+    match split_escaped(pair, '=').as_slice() {
+        [key, value] if !key.is_empty() => Ok((unescape(key), value)),
+        _ => Err(InfluxError::InvalidPair(pair.to_string()))
+    }
+}
+
+// Returns None for string fields which can not be stored
+fn parse_field_value(value: &str) -> Result<Option<f32>, InfluxError> {
+    if value.starts_with('"') {
+        return Ok(None);
+    }
+    let parsed = match value {
+        "t" | "T" | "true" | "True" | "TRUE" => Some(1.),
+        "f" | "F" | "false" | "False" | "FALSE" => Some(0.),
+        _ => value.trim_end_matches(&['i', 'u'][..]).parse::<f32>().ok()
+    };
+    parsed
+        .filter(|value| value.is_finite())
+        .map(Some)
+        .ok_or_else(|| InfluxError::InvalidValue(value.to_string()))
+}
+
+// Parses one line of line protocol. Every numeric field becomes a datapoint
+// named after the field, or after the measurement if the field is `value`
+pub fn parse_line(
+    stations: &[StationConfig],
+    line: &str,
+    precision: Precision
+) -> Result<Vec<Command>, InfluxError> {
+    let sections = split_escaped(line.trim(), ' ')
+        .into_iter()
+        .filter(|section| !section.is_empty())
+        .collect::<Vec<_>>();
+    let (key, fields, timestamp) = match sections.as_slice() {
+        [key, fields] => (key, fields, None),
+        [key, fields, timestamp] => (key, fields, Some(timestamp)),
+        _ => return Err(InfluxError::MissingFields(line.to_string()))
+    };
+
+    let mut key = split_escaped(key, ',').into_iter();
+    let measurement = unescape(key.next().unwrap_or(""));
+    let mut station = None;
+    for tag in key {
+        let (tag, value) = split_pair(tag)?;
+        if tag == STATION_TAG {
+            station = Some(unescape(value));
+        }
+    }
+
+    let timestamp = timestamp
+        .map(|timestamp| {
+            timestamp.parse::<i64>()
+                .map(|timestamp| precision.seconds(timestamp))
+                .map_err(|_| InfluxError::InvalidTimestamp(timestamp.to_string()))
+        })
+        .transpose()?;
+
+    let mut commands = vec!();
+    for field in split_escaped(fields, ',') {
+        let (field, value) = split_pair(field)?;
+        let value = match parse_field_value(value)? {
+            Some(value) => value,
+            None => continue
+        };
+
+        let series = if field == VALUE_FIELD { measurement.clone() } else { field };
+        let name = match &station {
+            Some(station) => config::series_name(stations, station, &series),
+            None => series
+        };
+        commands.push(Command::AddDatapoint(name, value, timestamp));
+    }
+
+    if commands.is_empty() {
+        Err(InfluxError::NoNumericFields(line.to_string()))
+    }
+    else {
+        Ok(commands)
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut result = String::new();
+    for c in s.chars() {
+        if c == ',' || c == ' ' || c == '=' || c == '\\' {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+// Exports the series as line protocol with one measurement per series. The
+// output parses back into the same series names. Quarantined values are left
+// out, they were rejected and their names would be read as station names
+pub fn export(
+    readings: &HashMap<String, Vec<Datapoint>>,
+    series: &HashMap<String, SeriesConfig>,
+    stations: &[StationConfig],
+    precision: Precision,
+    raw: bool
+) -> String {
+    let mut output = String::new();
+    for (name, data) in readings {
+        if name.ends_with(QUARANTINE_SUFFIX) {
+            continue;
+        }
+        let (station, plain_name) = config::split_series_name(stations, name);
+        let key = match station {
+            Some(station) => format!("{},{}={}", escape(plain_name), STATION_TAG, escape(station)),
+            None => escape(name)
+        };
+
+        let calibrations = match series.get(name) {
+            Some(config) if !raw => config.calibration.as_slice(),
+            _ => &[]
+        };
+        for point in calibration::calibrate_series(calibrations, data) {
+            output.push_str(&format!(
+                "{} {}={} {}\n",
+                key,
+                VALUE_FIELD,
+                point.value,
+                precision.timestamp(point.timestamp)
+            ));
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stations() -> Vec<StationConfig> {
+        vec!(StationConfig {
+            name: "garden".into(),
+            series: vec!("temperature".into()),
+            expected_interval: 300,
+            key: None,
+        })
+    }
+
+    fn parse(line: &str, precision: Precision) -> Result<Vec<(String, f32, Option<f64>)>, InfluxError> {
+        Ok(parse_line(&stations(), line, precision)?
+            .into_iter()
+            .map(|command| match command {
+                Command::AddDatapoint(name, value, timestamp) => (name, value, timestamp),
+                _ => panic!("Expected only datapoints")
+            })
+            .collect())
+    }
+
+    #[test]
+    fn fields_and_tags() {
+        assert_eq!(
+            parse("temperature,station=garden value=21.5", Precision::Seconds).unwrap(),
+            vec!(("temperature".into(), 21.5, None))
+        );
+        assert_eq!(
+            parse("weather,station=roof,sensor=bme280 temperature=20,humidity=60i 1600000000", Precision::Seconds).unwrap(),
+            vec!(("roof.temperature".into(), 20., Some(1600000000.)), ("roof.humidity".into(), 60., Some(1600000000.)))
+        );
+        assert_eq!(
+            parse("door open=t,locked=FALSE", Precision::Seconds).unwrap(),
+            vec!(("open".into(), 1., None), ("locked".into(), 0., None))
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            parse(r"outside\ temp,station=back\ yard value=1", Precision::Seconds).unwrap(),
+            vec!(("back yard.outside temp".into(), 1., None))
+        );
+        assert_eq!(
+            parse(r"wind gust\,max\=10=3", Precision::Seconds).unwrap(),
+            vec!(("gust,max=10".into(), 3., None))
+        );
+    }
+
+    #[test]
+    fn string_fields() {
+        assert_eq!(
+            parse(r#"weather note="cold, windy day=yes",temperature=5"#, Precision::Seconds).unwrap(),
+            vec!(("temperature".into(), 5., None))
+        );
+        assert!(matches!(
+            parse(r#"weather note="a \"quoted\" note""#, Precision::Seconds),
+            Err(InfluxError::NoNumericFields(_))
+        ));
+    }
+
+    #[test]
+    fn precisions() {
+        let timestamp = |line, precision| parse(line, precision).unwrap()[0].2;
+        assert_eq!(timestamp("t value=1 1600000000000", Precision::Milliseconds), Some(1600000000.));
+        assert_eq!(timestamp("t value=1 1600000000000000", Precision::Microseconds), Some(1600000000.));
+        assert_eq!(timestamp("t value=1 1600000000000000000", Precision::Nanoseconds), Some(1600000000.));
+        for line in &["t value=1 1600000000", "t value=1 1600000000000", "t value=1 1600000000000000000"] {
+            assert_eq!(timestamp(line, Precision::Auto), Some(1600000000.));
+        }
+
+        assert_eq!("ms".parse::<Precision>().unwrap(), Precision::Milliseconds);
+        assert_eq!("n".parse::<Precision>().unwrap(), Precision::Nanoseconds);
+        assert!(matches!("h".parse::<Precision>(), Err(InfluxError::UnknownPrecision(_))));
+        assert_eq!(Precision::Milliseconds.timestamp(1600000000.5), 1600000000500);
+    }
+
+    #[test]
+    fn invalid_lines() {
+        let error = |line| parse(line, Precision::Seconds).unwrap_err();
+        assert!(matches!(error("temperature"), InfluxError::MissingFields(_)));
+        assert!(matches!(error("temperature value=1 2 3"), InfluxError::MissingFields(_)));
+        assert!(matches!(error("temperature =1"), InfluxError::InvalidPair(_)));
+        assert!(matches!(error("temperature,station value=1"), InfluxError::InvalidPair(_)));
+        assert!(matches!(error("temperature value=warm"), InfluxError::InvalidValue(_)));
+        assert!(matches!(error("temperature value=inf"), InfluxError::InvalidValue(_)));
+        assert!(matches!(error("temperature value=1 soon"), InfluxError::InvalidTimestamp(_)));
+        assert!(matches!(error("temperature value=1 1600000000.5"), InfluxError::InvalidTimestamp(_)));
+    }
+
+    #[test]
+    fn export_parses_back() {
+        let mut readings = HashMap::new();
+        readings.insert("temperature".to_string(), vec!(Datapoint::new(1600000000., 21.5)));
+        readings.insert("roof.wind speed".to_string(), vec!(Datapoint::new(1600000060., 3.)));
+        readings.insert("temperature.quarantine".to_string(), vec!(Datapoint::new(1600000000., 80.)));
+
+        let exported = export(&readings, &HashMap::new(), &stations(), Precision::Seconds, false);
+        let mut parsed = exported.lines()
+            .flat_map(|line| parse(line, Precision::Seconds).unwrap())
+            .collect::<Vec<_>>();
+        parsed.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(parsed, vec!(
+            ("roof.wind speed".into(), 3., Some(1600000060.)),
+            ("temperature".into(), 21.5, Some(1600000000.)),
+        ));
+    }
+}
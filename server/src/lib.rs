@@ -0,0 +1,225 @@
+
+use std::sync::mpsc::{channel};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+extern crate serde_json;
+extern crate toml;
+#[macro_use]
+extern crate serde_derive;
+
+extern crate simple_server;
+extern crate http;
+extern crate chrono;
+#[macro_use]
+extern crate log;
+
+mod web;
+mod data_handler;
+mod types;
+mod simulator;
+mod logger;
+pub mod error;
+mod config;
+mod tcp_handler;
+mod udp_handler;
+mod postcard_handler;
+mod serial_handler;
+mod mqtt;
+mod home_assistant;
+mod constants;
+mod alerts;
+mod notifier;
+mod health;
+mod identity;
+mod events;
+mod filter;
+mod calibration;
+mod units;
+mod protocol;
+mod influx;
+mod series;
+mod shutdown;
+pub mod cli;
+mod maintenance;
+mod source;
+mod reload;
+
+#[cfg(feature = "raspi_nrf")]
+mod nrf24l01_reader;
+
+use error::Result;
+use color_anyhow::anyhow::{Context};
+
+// Runs the server, or the maintenance command in `args`, until it is stopped.
+// Logging is set up by the caller
+pub async fn run(args: cli::Args) -> Result<()> {
+    let mut config = config::read_config(&args.config)
+        .with_context(|| format!("Failed to read {:?}", args.config))?;
+    if let Some(data) = &args.data {
+        config.log_filename = data.clone();
+    }
+    log::set_max_level(reload::log_level(&config, args.log_level));
+
+    let notifiers = notifier::build_notifiers(&config.notifiers)
+        .context("Failed to set up notifiers")?;
+    notifier::warn_unknown_notifiers(&config.alerts, &notifiers);
+
+    let mut source_configs = config.sources.clone();
+    if args.demo {
+        source_configs.push(source::SourceConfig {
+            name: Some("demo".into()),
+            enabled: true,
+            kind: source::SourceKind::Simulator(simulator::SimulatorConfig {
+                seed: args.seed,
+                acceleration: args.acceleration,
+            }),
+        });
+    }
+    let sources = Arc::new(Mutex::new(
+        source::build_sources(&source_configs).context("Failed to set up sources")?
+    ));
+
+    if args.check_config {
+        println!("{:?} is valid", args.config);
+        return Ok(());
+    }
+    if let Some(command) = args.command {
+        return maintenance::run(command, &config);
+    }
+
+    let reading_collection = Arc::new(RwLock::new(
+        logger::load_data(&config.log_filename)
+            .with_context(|| format!("Failed to load {:?}", config.log_filename))?
+    ));
+
+    let metadata = Arc::new(Mutex::new(
+        logger::load_metadata(&config.metadata_filename)
+            .with_context(|| format!("Failed to load {:?}", config.metadata_filename))?
+    ));
+    let events = Arc::new(Mutex::new(
+        logger::load_events(&config.events_filename)
+            .with_context(|| format!("Failed to load {:?}", config.events_filename))?
+    ));
+    let counters = Arc::new(Mutex::new(
+        logger::load_counters(&config.counters_filename)
+            .with_context(|| format!("Failed to load {:?}", config.counters_filename))?
+    ));
+    let storage_files = logger::StorageFiles {
+        data: config.log_filename.clone(),
+        metadata: config.metadata_filename.clone(),
+        events: config.events_filename.clone(),
+        counters: config.counters_filename.clone(),
+    };
+
+    //let reading_collection = Arc::new(Mutex::new(HashMap::new()));
+    let (tx, rx) = tokio::sync::mpsc::channel(constants::COMMAND_QUEUE_LENGTH);
+
+    let (alert_tx, alert_rx) = channel();
+    let notifiers = Arc::new(Mutex::new(notifiers));
+    notifier::run_notifier(alert_rx, Arc::clone(&notifiers));
+    let alerts = Arc::new(Mutex::new(
+        alerts::AlertEngine::new(config.alerts.clone(), config.stations.clone(), alert_tx)
+    ));
+    alerts::run_alert_timer(Duration::from_secs(60), Arc::clone(&alerts));
+
+    let health = Arc::new(Mutex::new(health::HealthTracker::new(
+        config.stations.clone(),
+        config.series.clone(),
+        &reading_collection
+    )));
+    health::run_health_checker(Duration::from_secs(30), Arc::clone(&health));
+
+    let series_config = Arc::new(RwLock::new(config.series.clone()));
+    let ingest = protocol::Ingest {
+        stations: Arc::new(config.stations.clone()),
+        tx: tx.clone(),
+        stats: Arc::new(Mutex::new(protocol::ParseStats::default())),
+        counters: Arc::clone(&counters),
+    };
+
+    let subscribers = Arc::new(Mutex::new(vec!()));
+    let source_context = source::SourceContext {
+        ingest: ingest.clone(),
+        readings: Arc::clone(&reading_collection),
+        series: Arc::clone(&series_config),
+        subscribers: Arc::clone(&subscribers),
+    };
+    if sources.lock().unwrap().is_empty() {
+        warn!("No data sources are enabled, add them as [[sources]] in {:?}", args.config);
+    }
+    for source in sources.lock().unwrap().iter_mut() {
+        source.start(&source_context)
+            .with_context(|| format!("Failed to start source {}", source.name()))?;
+    }
+
+    let logger = logger::run_logger(
+            Duration::from_secs(60),
+            storage_files.clone(),
+            Arc::clone(&reading_collection),
+            Arc::clone(&metadata),
+            Arc::clone(&events),
+            Arc::clone(&counters)
+        );
+    let stopping = Arc::new(AtomicBool::new(false));
+    web::run_server(
+            config.http_address.clone(),
+            config.http_port,
+            web::WebState {
+                readings: Arc::clone(&reading_collection),
+                alerts: Arc::clone(&alerts),
+                health: Arc::clone(&health),
+                series: Arc::clone(&series_config),
+                metadata: Arc::clone(&metadata),
+                events: Arc::clone(&events),
+                sources: Arc::clone(&sources),
+                ingest: ingest.clone(),
+                stopping: Arc::clone(&stopping),
+                runtime: tokio::runtime::Handle::current(),
+            }
+        );
+    data_handler::run_command_handler(
+            rx,
+            data_handler::IngestState {
+                readings: Arc::clone(&reading_collection),
+                alerts: Arc::clone(&alerts),
+                health: Arc::clone(&health),
+                series: Arc::clone(&series_config),
+                subscribers,
+                metadata: Arc::clone(&metadata),
+                events: Arc::clone(&events),
+                counters,
+                files: storage_files,
+            }
+        );
+
+
+    let reloader = reload::run_reloader(
+        reload::Reloader {
+            path: args.config.clone(),
+            data: args.data.clone(),
+            log_level: args.log_level,
+            series: Arc::clone(&series_config),
+            alerts: Arc::clone(&alerts),
+            notifiers,
+            health: Arc::clone(&health),
+        },
+        config.clone()
+    )?;
+
+    shutdown::wait_for_signal().await?;
+    info!("Shutting down");
+    // The web server keeps running until the process exits
+    stopping.store(true, Ordering::Relaxed);
+    reloader.abort();
+    for source in sources.lock().unwrap().iter_mut() {
+        source.stop();
+    }
+    logger.stop();
+    shutdown::drain(&tx, Duration::from_secs(config.shutdown_timeout)).await?;
+
+    Ok(())
+}
+
+
@@ -1,44 +1,119 @@
 use std::time::Duration;
 use std::path::{Path, PathBuf};
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
 use std::collections::hash_map::HashMap;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
 use std::io::Write;
 
 use serde_json;
 use crate::error::Result;
-use crate::types::{ReadingCollection, Datapoint};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::types::{ReadingCollection, MetadataCollection, Datapoint};
+use crate::events::{EventStore, SharedEventStore};
+use crate::series;
+use crate::protocol::{Counters, SharedCounters};
 
 
-pub fn run_logger(interval: Duration, file: PathBuf, readings: ReadingCollection) {
-    thread::spawn(move || {
-        loop {
-            thread::sleep(interval);
-            {
-                info!("Saving data");
-                let readings = readings.lock().unwrap();
+// Where the data and metadata are saved
+#[derive(Clone)]
+pub struct StorageFiles {
+    pub data: PathBuf,
+    pub metadata: PathBuf,
+    pub events: PathBuf,
+    pub counters: PathBuf,
+}
+
+// The thread that saves everything periodically
+pub struct Logger {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
 
-                if let Err(e) = save_data(&file, &*readings) {
-                    error!("Failed to log data {:?}", e);
-                };
+impl Logger {
+    // Waits for a save that is in progress, so that it can not overlap with
+    // the final save when shutting down
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        if self.thread.join().is_err() {
+            error!("The logger thread panicked");
+        }
+    }
+}
 
-                info!("Data saved");
+pub fn run_logger(
+    interval: Duration,
+    files: StorageFiles,
+    readings: ReadingCollection,
+    metadata: MetadataCollection,
+    events: SharedEventStore,
+    counters: SharedCounters
+) -> Logger {
+    let (stop, stopped) = channel();
+    let thread = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            if let Err(e) = snapshot(&files, &readings, &metadata, &events, &counters) {
+                error!("Failed to log data {:?}", e);
             }
         }
     });
+    Logger { stop, thread }
+}
+
+pub fn snapshot(
+    files: &StorageFiles,
+    readings: &ReadingCollection,
+    metadata: &MetadataCollection,
+    events: &SharedEventStore,
+    counters: &SharedCounters
+) -> Result<()> {
+    info!("Saving data");
+    save_json(&files.data, &copy_readings(readings))?;
+    save_json(&files.metadata, &*metadata.lock().unwrap())?;
+    save_json(&files.events, &*events.lock().unwrap())?;
+    save_json(&files.counters, &*counters.lock().unwrap())?;
+    info!("Data saved");
+    Ok(())
+}
+
+// Copies the readings one series at a time, so that ingestion only waits for
+// the copy of one series instead of for everything that is done with the copy
+pub fn copy_readings(readings: &ReadingCollection) -> HashMap<String, Vec<Datapoint>> {
+    let names = readings.read().unwrap().keys().cloned().collect::<Vec<_>>();
+    names.into_iter()
+        .filter_map(|name| {
+            let data = readings.read().unwrap().get(&name)?.clone();
+            Some((name, data))
+        })
+        .collect()
 }
 
-fn save_data(filename: &Path, readings: &HashMap<String, Vec<Datapoint>>) -> Result<()>{
-    let saved_string = serde_json::to_string(readings)?;
+// Writes to a temporary file that then replaces the old one, so that the old
+// file is left as it was if saving fails or is cut off
+pub fn save_json(filename: &Path, value: &impl Serialize) -> Result<()>{
+    let saved_string = serde_json::to_string(value)?;
 
-    let mut file = OpenOptions::new().write(true).create(true).open(filename)?;
+    let mut temporary = filename.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    // Truncate since a save that failed may have left something behind
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&temporary)?;
     file.write_all(saved_string.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, filename)?;
 
     Ok(())
 }
 
-pub fn load_data(filename: &Path) -> Result<HashMap<String, Vec<Datapoint>>> {
+// A missing file is empty, like on the first start. Files that can not be read
+// or parsed are errors so that they are not overwritten by the next save
+fn load_json<T: DeserializeOwned + Default>(filename: &Path) -> Result<T> {
+    if !filename.exists() {
+        return Ok(T::default());
+    }
     let mut file = File::open(filename)?;
     let mut content = String::new();
     file.read_to_string(&mut content)?;
@@ -46,3 +121,22 @@ pub fn load_data(filename: &Path) -> Result<HashMap<String, Vec<Datapoint>>> {
     Ok(serde_json::from_str(&content)?)
 }
 
+pub fn load_data(filename: &Path) -> Result<HashMap<String, Vec<Datapoint>>> {
+    let mut data: HashMap<String, Vec<Datapoint>> = load_json(filename)?;
+    for points in data.values_mut() {
+        series::sort(points);
+    }
+    Ok(data)
+}
+
+pub fn load_metadata(filename: &Path) -> Result<HashMap<String, HashMap<String, String>>> {
+    load_json(filename)
+}
+
+pub fn load_events(filename: &Path) -> Result<EventStore> {
+    load_json(filename)
+}
+
+pub fn load_counters(filename: &Path) -> Result<Counters> {
+    load_json(filename)
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+
+use chrono::{TimeZone, Utc};
+use color_anyhow::anyhow::{anyhow, Context};
+
+use crate::cli::Maintenance;
+use crate::config::Config;
+use crate::data_handler;
+use crate::error::Result;
+use crate::influx::{self, Precision};
+use crate::logger;
+use crate::series::{self, Insertion};
+use crate::simulator;
+use crate::types::{Command, Datapoint, MetadataCollection, ReadingCollection};
+
+fn format_timestamp(timestamp: f64) -> String {
+    Utc.timestamp_opt(timestamp as i64, 0)
+        .single()
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+fn import(config: &Config, readings: &mut HashMap<String, Vec<Datapoint>>, content: &str, precision: Precision) -> Result<()> {
+    let now = Utc::now().timestamp() as f64;
+    let (mut inserted, mut duplicates) = (0, 0);
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let commands = influx::parse_line(&config.stations, line, precision)
+            .with_context(|| format!("Line {}", index + 1))?;
+        for command in commands {
+            if let Command::AddDatapoint(name, value, timestamp) = command {
+                let policy = config.series.get(&name).map(|config| config.duplicates).unwrap_or_default();
+                let point = Datapoint::new(timestamp.unwrap_or(now), value);
+                match series::insert(readings.entry(name).or_default(), point, policy) {
+                    Insertion::Inserted => inserted += 1,
+                    Insertion::Replaced | Insertion::Ignored => duplicates += 1,
+                }
+            }
+        }
+    }
+    println!("Imported {} points, {} had the timestamp of a stored point", inserted, duplicates);
+    Ok(())
+}
+
+// Works on its own file so that it can not mix with real data
+fn backfill(file: &Path, days: u32, seed: u64) -> Result<()> {
+    if file.exists() {
+        return Err(anyhow!("{:?} already exists", file));
+    }
+    let readings = simulator::backfill(seed, days);
+    println!("Simulated {} days with seed {}", days, seed);
+    logger::save_json(file, &readings)
+}
+
+fn list(readings: &HashMap<String, Vec<Datapoint>>) {
+    let mut names = readings.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        let data = &readings[name];
+        match (data.first(), data.last()) {
+            (Some(first), Some(last)) => println!(
+                "{}\t{} points\t{} .. {}",
+                name,
+                data.len(),
+                format_timestamp(first.timestamp),
+                format_timestamp(last.timestamp)
+            ),
+            _ => println!("{}\tempty", name),
+        }
+    }
+}
+
+// The stored data of the server
+struct Store {
+    readings: ReadingCollection,
+    metadata: MetadataCollection,
+}
+
+impl Store {
+    fn load(config: &Config) -> Result<Self> {
+        // A missing file is fine for an import into a new installation
+        let readings = logger::load_data(&config.log_filename)
+            .with_context(|| format!("Failed to load {:?}", config.log_filename))?;
+        let metadata = logger::load_metadata(&config.metadata_filename)
+            .with_context(|| format!("Failed to load {:?}", config.metadata_filename))?;
+        Ok(Self {
+            readings: Arc::new(RwLock::new(readings)),
+            metadata: Arc::new(Mutex::new(metadata)),
+        })
+    }
+
+    fn save_readings(&self, config: &Config) -> Result<()> {
+        logger::save_json(&config.log_filename, &*self.readings.read().unwrap())
+    }
+}
+
+// Runs a maintenance task on the files of `config`. The server should not be
+// running since it would overwrite the changes with its next snapshot
+pub fn run(command: Maintenance, config: &Config) -> Result<()> {
+    match command {
+        Maintenance::Backfill { file, days, seed } => backfill(&file, days, seed),
+        Maintenance::List => {
+            list(&Store::load(config)?.readings.read().unwrap());
+            Ok(())
+        }
+        Maintenance::Export { precision, raw } => {
+            let store = Store::load(config)?;
+            let readings = store.readings.read().unwrap();
+            print!("{}", influx::export(&readings, &config.series, &config.stations, precision, raw));
+            Ok(())
+        }
+        Maintenance::Rename { from, to } => {
+            let store = Store::load(config)?;
+            data_handler::rename(&store.readings, &store.metadata, &from, &to).map_err(|e| anyhow!(e))?;
+            logger::save_json(&config.metadata_filename, &*store.metadata.lock().unwrap())?;
+            store.save_readings(config)
+        }
+        Maintenance::Delete { series, from, to } => {
+            let store = Store::load(config)?;
+            let deleted = data_handler::delete_range(&store.readings, &series, from, to).map_err(|e| anyhow!(e))?;
+            println!("Deleted {} points", deleted.unwrap_or_default());
+            store.save_readings(config)
+        }
+        Maintenance::Import { file, precision } => {
+            let content = fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read {:?}", file))?;
+            let store = Store::load(config)?;
+            import(config, &mut store.readings.write().unwrap(), &content, precision)?;
+            store.save_readings(config)
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::HashSet;
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS, RecvTimeoutError};
+use tokio::runtime::Handle;
+
+use crate::config::{self, SharedSeriesConfig, StationConfig};
+use crate::home_assistant;
+use crate::protocol::{Ingest, Session};
+use crate::source::SourceState;
+use crate::types::{AcceptedDatapoint, Command};
+
+// Time to wait before reconnecting after a connection error
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// How long the threads wait for something to happen before checking whether
+// the source was stopped
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+// Messages waiting to be sent to the broker. Published values are dropped
+// when this is full
+const REQUEST_QUEUE_LENGTH: usize = 64;
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Readings are received on `<prefix>/<station>/<series>` and published to
+    // `<prefix>/<station>/<series>/state`
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    // Station that series which do not belong to a station are published under
+    #[serde(default = "default_station")]
+    pub default_station: String,
+    // Publish Home Assistant discovery configs for every series
+    #[serde(default)]
+    pub discovery: bool,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_port() -> u16 { 1883 }
+fn default_client_id() -> String { "weather-server".into() }
+fn default_topic_prefix() -> String { "weather".into() }
+fn default_station() -> String { "weather".into() }
+fn default_discovery_prefix() -> String { "homeassistant".into() }
+
+impl MqttConfig {
+    fn options(&self) -> MqttOptions {
+        let mut options = MqttOptions::new(&self.client_id, &self.host, self.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            options.set_credentials(username, password);
+        }
+        options
+    }
+
+    pub fn series_topic(&self, stations: &[StationConfig], name: &str) -> String {
+        let (station, series) = config::split_series_name(stations, name);
+        format!(
+            "{}/{}/{}",
+            self.topic_prefix,
+            station.unwrap_or(&self.default_station),
+            series
+        )
+    }
+}
+
+// Payloads are a value, optionally followed by `:<timestamp>`
+fn parse_message(
+    config: &MqttConfig,
+    stations: &[StationConfig],
+    topic: &str,
+    payload: &[u8]
+) -> Option<Command> {
+    let levels = topic.split('/').collect::<Vec<_>>();
+    let (station, series) = match levels.as_slice() {
+        [prefix, station, series] if *prefix == config.topic_prefix => (station, series),
+        _ => return None
+    };
+
+    let payload = std::str::from_utf8(payload).ok()?;
+    let mut split = payload.trim().split(':');
+    let value = split.next()?.parse::<f32>().ok().filter(|v| v.is_finite())?;
+    let timestamp = match split.next() {
+        Some(timestamp) => Some(timestamp.parse::<f64>().ok().filter(|t| t.is_finite())?),
+        None => None
+    };
+
+    Some(Command::AddDatapoint(config::series_name(stations, station, series), value, timestamp))
+}
+
+// Publishes every accepted datapoint, retained so that new subscribers get the
+// latest value right away. Calibration is the only thing derived from readings
+// on the server, so the calibrated value is published as the state and the raw
+// value next to it. Anything else, like a dew point, is a series of its own
+// that the stations send.
+// Values are published without waiting, while the broker can not be reached
+// they are dropped once the request queue is full. Waiting would leave the
+// accepted datapoints to pile up in `accepted` and keep the thread from
+// noticing that it was stopped
+fn run_publisher(
+    mut client: Client,
+    config: MqttConfig,
+    stations: Vec<StationConfig>,
+    series: SharedSeriesConfig,
+    known_series: Vec<String>,
+    accepted: Receiver<AcceptedDatapoint>,
+    state: SourceState
+) {
+    thread::spawn(move || {
+        let mut announced = HashSet::new();
+        let mut announce = |client: &mut Client, name: &str| {
+            if !config.discovery || !announced.insert(name.to_string()) {
+                return;
+            }
+            if let Some((topic, payload)) = home_assistant::discovery_message(&config, &stations, &series.read().unwrap(), name) {
+                if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, true, payload) {
+                    warn!("Failed to publish discovery config for {}, retrying with its next value: {:?}", name, e);
+                    announced.remove(name);
+                }
+            }
+        };
+
+        for name in &known_series {
+            announce(&mut client, name);
+        }
+
+        let mut dropped = 0;
+        // Dropping the receiver unsubscribes
+        while !state.is_stopped() {
+            let point = match accepted.recv_timeout(STOP_CHECK_INTERVAL) {
+                Ok(point) => point,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+            announce(&mut client, &point.name);
+
+            let topic = config.series_topic(&stations, &point.name);
+            let mut messages = vec!((format!("{}/state", topic), point.value));
+            // Calibrated series also get their raw values published
+            if point.raw != point.value {
+                messages.push((format!("{}/raw", topic), point.raw));
+            }
+
+            for (topic, value) in messages {
+                if client.try_publish(topic, QoS::AtLeastOnce, true, value.to_string()).is_err() {
+                    if dropped == 0 {
+                        warn!("MQTT request queue is full, dropping values until the broker can be reached");
+                    }
+                    dropped += 1;
+                }
+                else if dropped > 0 {
+                    info!("Publishing to MQTT again, {} values were dropped", dropped);
+                    dropped = 0;
+                }
+            }
+        }
+    });
+}
+
+// `known_series` are announced to Home Assistant right away, other series
+// once their first datapoint arrives. The returned thread receives messages,
+// which go through a session like on the other sources. Since MQTT messages
+// are not signed, series of stations with a key can not be written this way
+pub fn run_mqtt(
+    config: MqttConfig,
+    series: SharedSeriesConfig,
+    known_series: Vec<String>,
+    ingest: Ingest,
+    runtime: Handle,
+    accepted: Receiver<AcceptedDatapoint>,
+    state: SourceState
+) -> JoinHandle<()> {
+    let stations = ingest.stations.to_vec();
+    let (mut client, mut connection) = Client::new(config.options(), REQUEST_QUEUE_LENGTH);
+    run_publisher(client.clone(), config.clone(), stations.clone(), series, known_series, accepted, state.clone());
+
+    thread::spawn(move || {
+        let mut session = Session::new("mqtt");
+        let subscription = format!("{}/+/+", config.topic_prefix);
+        state.set_connecting(true);
+        while !state.is_stopped() {
+            let event = match connection.recv_timeout(STOP_CHECK_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            match event {
+                // Subscriptions do not survive reconnects so they are renewed
+                // on every connection
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker at {}:{}", config.host, config.port);
+                    state.set_connecting(false);
+                    if let Err(e) = client.subscribe(subscription.as_str(), QoS::AtLeastOnce) {
+                        error!("Failed to subscribe to {}: {:?}", subscription, e);
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    match parse_message(&config, &stations, &publish.topic, &publish.payload) {
+                        Some(command) => {
+                            if let Err(e) = runtime.block_on(session.execute(vec!(command), None, &ingest)) {
+                                warn!("Failed to handle MQTT message on {}: {}", publish.topic, e);
+                            }
+                        }
+                        None => warn!("Ignoring bad MQTT message on {}", publish.topic)
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection error: {:?}, reconnecting", e);
+                    state.set_connecting(true);
+                    thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::{mpsc, Arc, RwLock};
+    use std::time::Instant;
+
+    use tokio::time::timeout;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn config(host: &str, port: u16, prefix: &str) -> MqttConfig {
+        MqttConfig {
+            host: host.into(),
+            port,
+            client_id: format!("{}-server", prefix),
+            username: None,
+            password: None,
+            topic_prefix: prefix.into(),
+            default_station: "weather".into(),
+            discovery: false,
+            discovery_prefix: "homeassistant".into(),
+        }
+    }
+
+    #[test]
+    fn timestamps_must_be_finite() {
+        let config = config("localhost", 1883, "weather");
+        let parse = |payload: &str| parse_message(&config, &[], "weather/garden/temperature", payload.as_bytes());
+
+        assert!(matches!(parse("21.5:1600000000"), Some(Command::AddDatapoint(_, _, Some(_)))));
+        assert!(parse("21.5:NaN").is_none());
+        assert!(parse("21.5:inf").is_none());
+        assert!(parse("inf").is_none());
+    }
+
+    // Runs against a broker on localhost:1883, or MQTT_TEST_HOST and
+    // MQTT_TEST_PORT, for example `mosquitto -v`. Start it with
+    // `cargo test -- --ignored`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn against_broker() {
+        let host = std::env::var("MQTT_TEST_HOST").unwrap_or_else(|_| "localhost".into());
+        let port = std::env::var("MQTT_TEST_PORT").ok().and_then(|port| port.parse().ok()).unwrap_or(1883);
+        // Retained messages from earlier runs would get in the way
+        let prefix = format!("weather-test-{}", std::process::id());
+        let config = config(&host, port, &prefix);
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let ingest = Ingest::for_test(vec!(), tx);
+        let (accepted_tx, accepted_rx) = mpsc::channel();
+        run_mqtt(
+            config,
+            Arc::new(RwLock::new(HashMap::new())),
+            vec!(),
+            ingest,
+            Handle::current(),
+            accepted_rx,
+            SourceState::default()
+        );
+
+        let (mut client, mut connection) = Client::new(MqttOptions::new(format!("{}-client", prefix), host, port), 16);
+        client.subscribe(format!("{}/+/+/+", prefix), QoS::AtLeastOnce).unwrap();
+        let (published_tx, published_rx) = mpsc::channel();
+        thread::spawn(move || {
+            for event in connection.iter() {
+                if let Ok(Event::Incoming(Packet::Publish(publish))) = event {
+                    let payload = String::from_utf8(publish.payload.to_vec()).unwrap();
+                    if published_tx.send((publish.topic, payload)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        // The server may not have subscribed yet, so this is repeated until it arrives
+        let topic = format!("{}/garden/temperature", prefix);
+        let command = timeout(TIMEOUT, async {
+            loop {
+                client.publish(topic.as_str(), QoS::AtLeastOnce, false, "21.5:1600000000").unwrap();
+                if let Ok(Some(command)) = timeout(Duration::from_millis(500), rx.recv()).await {
+                    return command;
+                }
+            }
+        }).await.expect("no command received from MQTT");
+
+        let name = config::series_name(&[], "garden", "temperature");
+        match command {
+            Command::WithReply(command, reply) => {
+                match *command {
+                    Command::AddDatapoint(received, value, timestamp) => {
+                        assert_eq!(received, name);
+                        assert_eq!(value, 21.5);
+                        assert_eq!(timestamp, Some(1600000000.));
+                    }
+                    _ => panic!("expected a datapoint")
+                }
+                reply.send(Ok(None)).unwrap();
+            }
+            _ => panic!("expected a command that waits for a reply")
+        }
+
+        accepted_tx.send(AcceptedDatapoint { name, raw: 21., value: 21.5 }).unwrap();
+        let mut published = HashMap::new();
+        let deadline = Instant::now() + TIMEOUT;
+        while published.len() < 2 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let (topic, payload) = published_rx.recv_timeout(remaining).expect("datapoint was not published");
+            published.insert(topic, payload);
+        }
+        assert_eq!(published[&format!("{}/garden/temperature/state", prefix)], "21.5");
+        assert_eq!(published[&format!("{}/garden/temperature/raw", prefix)], "21");
+    }
+}
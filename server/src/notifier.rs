@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use color_anyhow::anyhow::Context;
+
+use thiserror::Error;
+
+use crate::alerts::{AlertEvent, AlertEventKind, AlertRule};
+use crate::error::Result;
+
+// Limit for connecting to and each read from or write to a webhook or SMTP
+// server. Alerts are sent one at a time, so a server that stops responding
+// would otherwise hold back all later alerts
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum NotifierError {
+    #[error("Only plain http:// webhook urls are supported, got {0}")]
+    UnsupportedUrl(String),
+    #[error("Webhook responded with: {0}")]
+    WebhookStatus(String),
+    #[error("Unexpected SMTP reply, expected {0}, got {1}")]
+    SmtpReply(u16, String),
+    #[error("Notification command exited with {0}")]
+    CommandFailed(process::ExitStatus),
+    #[error("Duplicate notifier name {0}")]
+    DuplicateName(String),
+    #[error("{0} did not resolve to any address")]
+    NoAddress(String),
+}
+
+pub trait Notifier: Send + Sync {
+    fn notify(&self, event: &AlertEvent) -> Result<()>;
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NotifierConfig {
+    Log { name: String },
+    Webhook { name: String, url: String },
+    Smtp { name: String, server: String, from: String, to: Vec<String> },
+    Command {
+        name: String,
+        command: String,
+        #[serde(default)]
+        args: Vec<String>
+    },
+}
+
+impl NotifierConfig {
+    pub fn name(&self) -> &str {
+        match self {
+            NotifierConfig::Log { name }
+            | NotifierConfig::Webhook { name, .. }
+            | NotifierConfig::Smtp { name, .. }
+            | NotifierConfig::Command { name, .. } => name,
+        }
+    }
+
+    fn build(&self) -> Result<Arc<dyn Notifier>> {
+        Ok(match self {
+            NotifierConfig::Log { .. } => Arc::new(LogNotifier),
+            NotifierConfig::Webhook { url, .. } => Arc::new(WebhookNotifier::new(url)?),
+            NotifierConfig::Smtp { server, from, to, .. } => Arc::new(SmtpNotifier {
+                server: server.clone(),
+                from: from.clone(),
+                to: to.clone(),
+            }),
+            NotifierConfig::Command { command, args, .. } => Arc::new(CommandNotifier {
+                command: command.clone(),
+                args: args.clone(),
+            }),
+        })
+    }
+}
+
+fn connect(address: &str) -> Result<TcpStream> {
+    let address = address.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| NotifierError::NoAddress(address.to_string()))?;
+    let stream = TcpStream::connect_timeout(&address, NETWORK_TIMEOUT)?;
+    stream.set_read_timeout(Some(NETWORK_TIMEOUT))?;
+    stream.set_write_timeout(Some(NETWORK_TIMEOUT))?;
+    Ok(stream)
+}
+
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn notify(&self, event: &AlertEvent) -> Result<()> {
+        match event.kind {
+            AlertEventKind::Triggered => warn!("{}", event.message),
+            AlertEventKind::Resolved => info!("{}", event.message),
+        }
+        Ok(())
+    }
+}
+
+pub struct WebhookNotifier {
+    host: String,
+    path: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Result<Self> {
+        let without_scheme = url.strip_prefix("http://")
+            .ok_or_else(|| NotifierError::UnsupportedUrl(url.to_string()))?;
+
+        let (host, path) = match without_scheme.find('/') {
+            Some(index) => without_scheme.split_at(index),
+            None => (without_scheme, "/"),
+        };
+        let host = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+
+        Ok(Self { host, path: path.to_string() })
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, event: &AlertEvent) -> Result<()> {
+        let body = serde_json::to_string(event)?;
+
+        let mut stream = connect(&self.host)
+            .with_context(|| format!("Failed to connect to webhook at {}", self.host))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        )?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)
+            .with_context(|| format!("No response from webhook at {}", self.host))?;
+
+        match status_line.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(NotifierError::WebhookStatus(status_line.trim().to_string()).into())
+        }
+    }
+}
+
+// Sends mail through a plain SMTP relay, typically one running on the same
+// machine. No TLS or authentication is done
+pub struct SmtpNotifier {
+    server: String,
+    from: String,
+    to: Vec<String>,
+}
+
+impl SmtpNotifier {
+    fn command(
+        reader: &mut BufReader<TcpStream>,
+        command: &str,
+        expected: u16
+    ) -> Result<()> {
+        if !command.is_empty() {
+            write!(reader.get_mut(), "{}\r\n", command)?;
+        }
+
+        // Multiline replies have a '-' after the status code on all but the last line
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            if !line.starts_with(&expected.to_string()) {
+                return Err(NotifierError::SmtpReply(expected, line.trim().to_string()).into());
+            }
+            if line.chars().nth(3) != Some('-') {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Notifier for SmtpNotifier {
+    fn notify(&self, event: &AlertEvent) -> Result<()> {
+        let stream = connect(&self.server)
+            .with_context(|| format!("Failed to connect to SMTP server at {}", self.server))?;
+        let mut reader = BufReader::new(stream);
+
+        Self::command(&mut reader, "", 220)?;
+        Self::command(&mut reader, "HELO weather", 250)?;
+        Self::command(&mut reader, &format!("MAIL FROM:<{}>", self.from), 250)?;
+        for to in &self.to {
+            Self::command(&mut reader, &format!("RCPT TO:<{}>", to), 250)?;
+        }
+        Self::command(&mut reader, "DATA", 354)?;
+
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: [weather] {}\r\n\r\n{}\r\n.",
+            self.from,
+            self.to.join(", "),
+            event.message,
+            serde_json::to_string_pretty(event)?
+        );
+        Self::command(&mut reader, &message, 250)?;
+        Self::command(&mut reader, "QUIT", 221)
+    }
+}
+
+// Runs a local command with the alert passed through environment variables
+pub struct CommandNotifier {
+    command: String,
+    args: Vec<String>,
+}
+
+impl Notifier for CommandNotifier {
+    fn notify(&self, event: &AlertEvent) -> Result<()> {
+        let kind = match event.kind {
+            AlertEventKind::Triggered => "triggered",
+            AlertEventKind::Resolved => "resolved",
+        };
+
+        let status = process::Command::new(&self.command)
+            .args(&self.args)
+            .env("ALERT_RULE", &event.rule)
+            .env("ALERT_SERIES", &event.series)
+            .env("ALERT_STATION", event.station.as_deref().unwrap_or_default())
+            .env("ALERT_KIND", kind)
+            .env("ALERT_VALUE", event.value.map(|v| v.to_string()).unwrap_or_default())
+            .env("ALERT_MESSAGE", &event.message)
+            .status()
+            .with_context(|| format!("Failed to run {}", self.command))?;
+
+        if status.success() {
+            Ok(())
+        }
+        else {
+            Err(NotifierError::CommandFailed(status).into())
+        }
+    }
+}
+
+pub type Notifiers = HashMap<String, Arc<dyn Notifier>>;
+// Replaced when the config is reloaded
+pub type SharedNotifiers = Arc<Mutex<Notifiers>>;
+
+pub fn build_notifiers(configs: &[NotifierConfig]) -> Result<Notifiers> {
+    let mut notifiers: Notifiers = HashMap::new();
+    notifiers.insert("log".into(), Arc::new(LogNotifier));
+
+    for config in configs {
+        if config.name() != "log" && notifiers.contains_key(config.name()) {
+            Err(NotifierError::DuplicateName(config.name().to_string()))?
+        }
+        notifiers.insert(config.name().to_string(), config.build()?);
+    }
+    Ok(notifiers)
+}
+
+pub fn warn_unknown_notifiers(rules: &[AlertRule], notifiers: &Notifiers) {
+    for rule in rules {
+        for name in rule.notify.iter().filter(|name| !notifiers.contains_key(*name)) {
+            warn!("Alert {} uses unknown notifier {}", rule.name, name);
+        }
+    }
+}
+
+pub fn run_notifier(
+    rx: Receiver<(Vec<String>, AlertEvent)>,
+    notifiers: SharedNotifiers
+) {
+    thread::spawn(move || {
+        for (names, event) in rx {
+            // Sending can take a while, the lock is only held to pick the
+            // notifiers so that reloading does not have to wait for it
+            let targets = notifiers.lock().unwrap()
+                .iter()
+                .filter(|(name, _)| names.is_empty() || names.contains(name))
+                .map(|(name, notifier)| (name.clone(), Arc::clone(notifier)))
+                .collect::<Vec<_>>();
+
+            for (name, notifier) in targets {
+                if let Err(e) = notifier.notify(&event) {
+                    error!("Notifier {} failed: {:?}", name, e);
+                }
+            }
+        }
+    });
+}
@@ -1,58 +1,365 @@
-// use std::sync::mpsc::Sender;
-// use std::sync::Arc;
-// use std::thread::sleep;
-// use std::time::Duration;
-// 
-// use rppal::spi::{Spi, Bus, SlaveSelect, Mode};
-// use rppal::gpio::Gpio;
-// 
-// use embedded_nrf24l01 as nrf;
-// use nrf::Configuration;
-// use common::nrf::setup_nrf;
-// 
-// use types::Command;
-// 
-// fn run_nrf_reader(tx: Arc<Sender<Command>>) {
-//     std::thread::spawn(|| {
-//         let spi = Spi::new(
-//             Bus::Spi0,
-//             SlaveSelect::Ss0,
-//             nrf::setup::clock_mhz() * 1_000_000,
-//             Mode::Mode0,
-//         ).expect("Failed to initialise SPI");
-// 
-//         let gpio = Gpio::new().expect("Failed to get GPIO peripheral");
-// 
-//         let ce = gpio.get(22).expect("Failed to get ce pin").into_output();
-//         let csn = gpio.get(27).expect("Failed to get cs pin").into_output();
-// 
-//         let mut nrf = nrf::NRF24L01::new(ce, csn, spi)
-//             .expect("Failed to initialise nrf");
-// 
-//         let addr: [u8; 5] = [0x22, 0x22, 0x22, 0x22, 0x22];
-// 
-//         setup_nrf(&mut nrf, &addr);
-//         sleep(Duration::from_millis(10));
-// 
-//         let mut nrf = nrf
-//             .rx()
-//             .expect("Failed to go into RX mode");
-//         sleep(Duration::from_millis(130));
-// 
-//         // nrf.set_tx_addr(b"00001").expect("failed to set addres");
-// 
-// 
-//         loop {
-//             while let Some(chan) = nrf.can_read().expect("Failed to check for msgs") {
-//                 println!("Got a message on channel: {}", chan);
-//                 let message = nrf.read().expect("Failed to read");
-//                 let decoded = postcard::from_bytes::<common::Message>(&message);
-// 
-//                 println!("Msg: {:?}", decoded);
-//                 // println!("Content: {}", String::from_utf8_lossy(&message));
-//             }
-//             println!("{:?}", nrf.can_read());
-//             sleep(Duration::from_millis(1000));
-//         }
-//     })
-// }
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+use rppal::gpio::Gpio;
+use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+use tokio::runtime::Handle;
+
+use embedded_nrf24l01 as nrf;
+use nrf::{Device, RxMode, NRF24L01};
+use common::nrf::setup_nrf;
+
+use color_anyhow::anyhow::{anyhow, Context};
+
+use crate::error::Result;
+use crate::postcard_handler;
+use crate::protocol::{Ingest, Session};
+use crate::source::SourceState;
+use crate::types::Command;
+
+// The radio has no interrupt line connected so it is polled
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Time for the radio to settle after being configured and after entering RX mode
+const SETUP_DELAY: Duration = Duration::from_millis(10);
+const RX_DELAY: Duration = Duration::from_millis(130);
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct NrfConfig {
+    // BCM numbers of the pins, the radio is on SPI0
+    #[serde(default = "default_ce_pin")]
+    pub ce_pin: u8,
+    #[serde(default = "default_csn_pin")]
+    pub csn_pin: u8,
+    // Must match the address that the stations send to
+    #[serde(default = "default_address")]
+    pub address: [u8; 5],
+}
+
+fn default_ce_pin() -> u8 { 22 }
+fn default_csn_pin() -> u8 { 27 }
+fn default_address() -> [u8; 5] { [0x22; 5] }
+
+// Configures the radio and starts listening. Works with anything that
+// implements the embedded-hal traits, not only the Raspberry Pi peripherals
+pub fn start_radio<E, CE, CSN, SPI, SPIE>(
+    ce: CE,
+    csn: CSN,
+    spi: SPI,
+    address: &[u8]
+) -> Result<RxMode<NRF24L01<E, CE, CSN, SPI>>>
+where
+    E: Debug,
+    CE: OutputPin<Error = E>,
+    CSN: OutputPin<Error = E>,
+    SPI: Transfer<u8, Error = SPIE>,
+    SPIE: Debug,
+{
+    let mut radio = NRF24L01::new(ce, csn, spi)
+        .map_err(|e| anyhow!("Failed to initialise the radio: {:?}", e))?;
+
+    setup_nrf(&mut radio, address)
+        .map_err(|e| anyhow!("Failed to configure the radio: {:?}", e))?;
+    thread::sleep(SETUP_DELAY);
+
+    let radio = radio.rx()
+        .map_err(|(_, e)| anyhow!("Failed to go into RX mode: {:?}", e))?;
+    thread::sleep(RX_DELAY);
+    Ok(radio)
+}
+
+// Executes a packet in the session of the station number that sent it. A hello
+// starts a new session, for example after the station rebooted. Packets that
+// arrive before the first hello of a station are not attributed to it
+fn handle_packet(
+    bytes: &[u8],
+    sessions: &mut HashMap<u8, Session>,
+    ingest: &Ingest,
+    runtime: &Handle
+) {
+    let decoded = postcard_handler::decode_packet(bytes);
+    ingest.stats.lock().unwrap().record(&decoded);
+
+    let (station, command, auth) = match decoded {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            warn!("Ignoring bad radio packet: {}", e);
+            return;
+        }
+    };
+
+    let source = || Session::new(format!("radio:{}", station));
+    let session = match command {
+        Command::Hello(_) => {
+            sessions.insert(station, source());
+            sessions.get_mut(&station).unwrap()
+        }
+        _ => sessions.entry(station).or_insert_with(source)
+    };
+
+    if let Err(e) = runtime.block_on(session.execute(vec!(command), auth, ingest)) {
+        warn!("Failed to handle radio packet from {}: {}", session.source, e);
+    }
+}
+
+// Reads packets until the radio fails or the source is stopped
+pub fn receive<D>(mut radio: RxMode<D>, ingest: &Ingest, runtime: &Handle, state: &SourceState) -> Result<()>
+where
+    D: Device,
+    D::Error: Debug,
+{
+    let mut sessions = HashMap::new();
+    while !state.is_stopped() {
+        while let Some(pipe) = radio.can_read().map_err(|e| anyhow!("Failed to poll the radio: {:?}", e))? {
+            let payload = radio.read().map_err(|e| anyhow!("Failed to read from the radio: {:?}", e))?;
+            debug!("Got {} bytes on pipe {}", payload.len(), pipe);
+            handle_packet(&payload, &mut sessions, ingest, runtime);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+fn run_radio(config: &NrfConfig, ingest: &Ingest, runtime: &Handle, state: &SourceState) -> Result<()> {
+    let spi = Spi::new(
+        Bus::Spi0,
+        SlaveSelect::Ss0,
+        nrf::setup::clock_mhz() * 1_000_000,
+        Mode::Mode0,
+    ).context("Failed to initialise SPI")?;
+
+    let gpio = Gpio::new().context("Failed to get GPIO peripheral")?;
+    let ce = gpio.get(config.ce_pin).context("Failed to get CE pin")?.into_output();
+    let csn = gpio.get(config.csn_pin).context("Failed to get CSN pin")?.into_output();
+
+    let radio = start_radio(ce, csn, spi, &config.address)?;
+    info!("Listening for radio packets");
+    receive(radio, ingest, runtime, state)
+}
+
+// Reads packets from an NRF24L01 connected to the Raspberry Pi on its own thread
+pub fn run_nrf_reader(config: NrfConfig, ingest: Ingest, runtime: Handle, state: SourceState) -> JoinHandle<()> {
+    thread::spawn(move || {
+        if let Err(e) = run_radio(&config, &ingest, &runtime, &state) {
+            error!("Radio reader stopped: {:#}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    use common::{DeciCelcius, Header, Message, Packet, SensorReading};
+
+    const RF_CH: u8 = 0x05;
+    const RX_ADDR_P0: u8 = 0x0a;
+    const FIFO_STATUS: u8 = 0x17;
+
+    // Answers SPI commands like an nRF24L01 would. Packets on the air end up
+    // in the RX FIFO once CE is raised
+    #[derive(Default)]
+    struct Radio {
+        registers: HashMap<u8, Vec<u8>>,
+        ce: bool,
+        air: VecDeque<Vec<u8>>,
+        rx_fifo: VecDeque<Vec<u8>>,
+    }
+
+    impl Radio {
+        fn new(packets: Vec<Vec<u8>>) -> Self {
+            let mut registers = HashMap::new();
+            // Reset values from the datasheet that the driver checks
+            registers.insert(0x00, vec!(0x08));
+            registers.insert(0x03, vec!(0x03));
+            Self { registers, air: packets.into(), ..Default::default() }
+        }
+
+        fn status(&self) -> u8 {
+            // The pipe number of the first packet, or 0b111 when there is none
+            let pipe = if self.rx_fifo.is_empty() { 0b111 } else { 0 };
+            0x0e & (pipe << 1)
+        }
+
+        fn command(&mut self, buf: &mut [u8]) {
+            let command = buf[0];
+            buf[0] = self.status();
+            let data = &mut buf[1..];
+            match command {
+                0x00..=0x1f if command == FIFO_STATUS => {
+                    // TX is always empty
+                    data[0] = 0x10 | self.rx_fifo.is_empty() as u8;
+                }
+                0x00..=0x1f => {
+                    let value = self.registers.get(&command).cloned().unwrap_or_default();
+                    for (byte, value) in data.iter_mut().zip(value.iter().chain(std::iter::repeat(&0))) {
+                        *byte = *value;
+                    }
+                }
+                0x20..=0x3f => {
+                    self.registers.insert(command & 0x1f, data.to_vec());
+                }
+                // R_RX_PL_WID
+                0x60 => data[0] = self.rx_fifo.front().map(Vec::len).unwrap_or(0) as u8,
+                // R_RX_PAYLOAD
+                0x61 => {
+                    let payload = self.rx_fifo.pop_front().unwrap_or_default();
+                    data[..payload.len()].copy_from_slice(&payload);
+                }
+                // FLUSH_RX
+                0xe2 => self.rx_fifo.clear(),
+                _ => {}
+            }
+        }
+    }
+
+    type SharedRadio = Arc<Mutex<Radio>>;
+
+    struct Spi(SharedRadio);
+
+    impl Transfer<u8> for Spi {
+        type Error = Infallible;
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> std::result::Result<&'w [u8], Infallible> {
+            self.0.lock().unwrap().command(words);
+            Ok(words)
+        }
+    }
+
+    struct Ce(SharedRadio);
+
+    impl OutputPin for Ce {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> std::result::Result<(), Infallible> {
+            self.0.lock().unwrap().ce = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> std::result::Result<(), Infallible> {
+            let mut radio = self.0.lock().unwrap();
+            radio.ce = true;
+            let packets = radio.air.drain(..).collect::<Vec<_>>();
+            radio.rx_fifo.extend(packets);
+            Ok(())
+        }
+    }
+
+    struct Csn;
+
+    impl OutputPin for Csn {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> std::result::Result<(), Infallible> { Ok(()) }
+        fn set_high(&mut self) -> std::result::Result<(), Infallible> { Ok(()) }
+    }
+
+    fn packet(message: Message) -> Vec<u8> {
+        let mut buffer = [0; 64];
+        postcard::to_slice(&Packet { station: 3, message }, &mut buffer).unwrap().to_vec()
+    }
+
+    fn hello() -> Vec<u8> {
+        packet(Message::Hello(Header {
+            station: "roof",
+            firmware_version: "1.0",
+            protocol_version: common::PROTOCOL_VERSION,
+            boot_counter: 1,
+        }))
+    }
+
+    fn temperature(decicelcius: i32) -> Vec<u8> {
+        packet(Message::Reading(SensorReading::Temperature(DeciCelcius(decicelcius))))
+    }
+
+    fn ingest() -> (Ingest, tokio::sync::mpsc::Receiver<Command>) {
+        let (tx, rx) = tokio::sync::mpsc::channel(8);
+        (Ingest::for_test(vec!(), tx), rx)
+    }
+
+    #[test]
+    fn packets_are_received() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let radio = Arc::new(Mutex::new(Radio::new(vec!(hello(), temperature(215), temperature(-30)))));
+        let address = [0x22; 5];
+
+        let rx_mode = start_radio(Ce(Arc::clone(&radio)), Csn, Spi(Arc::clone(&radio)), &address).unwrap();
+        {
+            let radio = radio.lock().unwrap();
+            assert_eq!(radio.registers[&RF_CH], vec!(100));
+            assert_eq!(radio.registers[&RX_ADDR_P0], address.to_vec());
+            assert!(radio.ce);
+        }
+
+        let (ingest, mut rx) = ingest();
+        let state = SourceState::default();
+        let reader = {
+            let (handle, state) = (runtime.handle().clone(), state.clone());
+            thread::spawn(move || receive(rx_mode, &ingest, &handle, &state))
+        };
+
+        let mut values = vec!();
+        let mut hellos = vec!();
+        while values.len() < 2 {
+            match runtime.block_on(rx.recv()).unwrap() {
+                Command::WithReply(command, reply) => {
+                    match *command {
+                        Command::AddDatapoint(name, value, _) => values.push((name, value)),
+                        Command::Hello(identity) => hellos.push(identity.station),
+                        _ => {}
+                    }
+                    reply.send(Ok(None)).unwrap();
+                }
+                _ => panic!("Expected a command with a reply"),
+            }
+        }
+        state.stop();
+        reader.join().unwrap().unwrap();
+
+        // Series of stations that are not in the config are prefixed with the station
+        assert_eq!(values, vec!(("roof.temperature".to_string(), 21.5), ("roof.temperature".to_string(), -3.)));
+        // The readings refer to the hello by the station number
+        assert_eq!(hellos, vec!("roof".to_string()));
+    }
+
+    #[test]
+    fn signed_hellos_fit() {
+        let hello = Packet {
+            station: 255,
+            message: Message::Hello(Header {
+                station: "garden",
+                firmware_version: "1.0",
+                protocol_version: common::PROTOCOL_VERSION,
+                boot_counter: u32::MAX,
+            }),
+        };
+        let mut buffer = [0; 64];
+        let length = postcard::to_slice(&hello, &mut buffer).unwrap().len();
+        let length = common::auth::sign_in_place(b"secret", 1, &mut buffer, length).unwrap();
+        assert!(length <= common::MAX_PACKET_LENGTH);
+
+        let (station, command, auth) = postcard_handler::decode_packet(&buffer[..length]).unwrap();
+        assert_eq!(station, 255);
+        assert!(matches!(command, Command::Hello(identity) if identity.station == "garden"));
+        assert_eq!(auth.map(|auth| auth.counter), Some(1));
+    }
+
+    #[test]
+    fn oversized_packets_are_dropped() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (ingest, mut rx) = ingest();
+        let mut bytes = temperature(215);
+        bytes.resize(common::MAX_PACKET_LENGTH + 1, 0);
+
+        handle_packet(&bytes, &mut HashMap::new(), &ingest, runtime.handle());
+        assert!(rx.try_recv().is_err());
+        assert_eq!(ingest.stats.lock().unwrap().rejected.get("too_long"), Some(&1));
+    }
+}
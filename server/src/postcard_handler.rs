@@ -0,0 +1,285 @@
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::timeout;
+
+use common::{auth, Message, SensorReading};
+
+use crate::types::Command;
+use crate::events::{Event, Severity};
+use crate::identity::Identity;
+use crate::error::Result;
+use crate::protocol::{Auth, Ingest, ParseError, Session};
+
+// Frames are prefixed by their length as a big endian u16
+const LENGTH_PREFIX_SIZE: usize = 2;
+const MAX_FRAME_LENGTH: usize = 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn reading_datapoint(reading: &SensorReading) -> (&'static str, f32) {
+    match reading {
+        SensorReading::Temperature(decicelcius) => ("temperature", decicelcius.0 as f32 / 10.),
+        SensorReading::Pressure(pascal) => ("pressure", pascal.0 as f32 / 100.),
+        SensorReading::Battery(volts) => ("battery", *volts),
+    }
+}
+
+pub fn message_command(message: &Message) -> std::result::Result<Command, ParseError> {
+    Ok(match message {
+        Message::Reading(reading) => {
+            let (name, value) = reading_datapoint(reading);
+            if !value.is_finite() {
+                return Err(ParseError::InvalidValue(value.to_string()));
+            }
+            Command::AddDatapoint(name.to_string(), value, None)
+        }
+        // Errors the station has run into since its last transmission
+        Message::Error(error) => Command::Event(Event::new(Severity::Error, error)),
+        Message::Hello(header) => Command::Hello(Identity::from(header)),
+    })
+}
+
+// The counter and tag that follow a signed message, `rest` is what is left of
+// `bytes` after the message
+fn trailer_auth(bytes: &[u8], rest: &[u8]) -> std::result::Result<Option<Auth>, ParseError> {
+    if rest.is_empty() {
+        return Ok(None);
+    }
+    let (counter, tag) = auth::split_trailer(rest)
+        .ok_or_else(|| ParseError::InvalidAuth(format!("{} trailing bytes", rest.len())))?;
+    Ok(Some(Auth {
+        counter,
+        tag: tag.to_vec(),
+        message: bytes[..bytes.len() - rest.len()].to_vec(),
+    }))
+}
+
+// Decodes a frame, optionally followed by the counter and tag of a signed message
+pub fn decode_frame(bytes: &[u8]) -> std::result::Result<(Command, Option<Auth>), ParseError> {
+    let (message, rest) = postcard::take_from_bytes::<Message>(bytes)
+        .map_err(|e| ParseError::InvalidFrame(format!("{:?}", e)))?;
+    Ok((message_command(&message)?, trailer_auth(bytes, rest)?))
+}
+
+// Radio packets have no connection, every packet starts with the number of the
+// station that sent it instead
+#[cfg(feature = "raspi_nrf")]
+pub fn decode_packet(bytes: &[u8]) -> std::result::Result<(u8, Command, Option<Auth>), ParseError> {
+    if bytes.len() > common::MAX_PACKET_LENGTH {
+        return Err(ParseError::TooLong(common::MAX_PACKET_LENGTH));
+    }
+    let (packet, rest) = postcard::take_from_bytes::<common::Packet>(bytes)
+        .map_err(|e| ParseError::InvalidFrame(format!("{:?}", e)))?;
+    Ok((packet.station, message_command(&packet.message)?, trailer_auth(bytes, rest)?))
+}
+
+// Splits a buffer of length prefixed frames. A truncated frame at the end is an error
+pub fn split_frames(mut bytes: &[u8]) -> std::result::Result<Vec<&[u8]>, ParseError> {
+    let mut frames = vec!();
+    while !bytes.is_empty() {
+        if bytes.len() < LENGTH_PREFIX_SIZE {
+            return Err(ParseError::InvalidFrame("truncated length prefix".into()));
+        }
+        let length = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+        let rest = &bytes[LENGTH_PREFIX_SIZE..];
+        if rest.len() < length {
+            return Err(ParseError::InvalidFrame(format!("expected {} bytes, got {}", length, rest.len())));
+        }
+        frames.push(&rest[..length]);
+        bytes = &rest[length..];
+    }
+    Ok(frames)
+}
+
+// Decodes and executes a frame. The stations do not wait for replies so
+// errors are only logged
+pub async fn handle_frame(
+    bytes: &[u8],
+    session: &mut Session,
+    ingest: &Ingest
+) {
+    let decoded = decode_frame(bytes);
+    ingest.stats.lock().unwrap().record(&decoded);
+
+    match decoded {
+        Ok((command, auth)) => {
+            if let Err(e) = session.execute(vec!(command), auth, ingest).await {
+                warn!("Failed to handle frame from {}: {}", session.source, e);
+            }
+        }
+        Err(e) => warn!("Ignoring bad frame from {}: {}", session.source, e),
+    }
+}
+
+// Reads one frame. Returns None at EOF
+async fn read_frame(
+    stream: &mut (impl AsyncRead + Unpin)
+) -> io::Result<Option<std::result::Result<Vec<u8>, ParseError>>> {
+    let mut prefix = [0; LENGTH_PREFIX_SIZE];
+    match stream.read_exact(&mut prefix).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let length = u16::from_be_bytes(prefix) as usize;
+    // Frames that are too long are skipped without reading them into memory
+    if length > MAX_FRAME_LENGTH {
+        let skipped = tokio::io::copy(&mut (&mut *stream).take(length as u64), &mut tokio::io::sink()).await?;
+        if skipped < length as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        return Ok(Some(Err(ParseError::TooLong(MAX_FRAME_LENGTH))));
+    }
+
+    let mut buffer = vec![0; length];
+    stream.read_exact(&mut buffer).await?;
+    Ok(Some(Ok(buffer)))
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    ingest: &Ingest
+) -> Result<()> {
+    let mut session = Session::new(stream.peer_addr()?.ip().to_string());
+
+    while let Some(frame) = timeout(READ_TIMEOUT, read_frame(&mut stream)).await?? {
+        match frame {
+            Ok(frame) => handle_frame(&frame, &mut session, ingest).await,
+            Err(e) => {
+                warn!("Ignoring bad frame from {}: {}", session.source, e);
+                ingest.stats.lock().unwrap().record::<()>(&Err(e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn run_postcard_handler(
+    listener: TcpListener,
+    ingest: Ingest
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // Connections are aborted together with this task
+        let mut connections = JoinSet::new();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                Some(_) = connections.join_next() => continue,
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept postcard connection: {:?}", e);
+                    continue;
+                }
+            };
+            info!("New postcard connection from {}", peer);
+
+            let ingest = ingest.clone();
+            connections.spawn(async move {
+                if let Err(e) = handle_connection(stream, &ingest).await {
+                    warn!("Postcard connection closed with error: {:?}", e);
+                }
+            });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use common::DeciCelcius;
+
+    fn encode(message: &Message) -> Vec<u8> {
+        let mut buffer = [0; 64];
+        postcard::to_slice(message, &mut buffer).unwrap().to_vec()
+    }
+
+    fn temperature(decicelcius: i32) -> Vec<u8> {
+        encode(&Message::Reading(SensorReading::Temperature(DeciCelcius(decicelcius))))
+    }
+
+    fn prefixed(frames: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = vec!();
+        for frame in frames {
+            bytes.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(frame);
+        }
+        bytes
+    }
+
+    #[test]
+    fn frames_are_decoded() {
+        assert!(matches!(
+            decode_frame(&temperature(215)),
+            Ok((Command::AddDatapoint(name, value, None), None)) if name == "temperature" && value == 21.5
+        ));
+        assert!(matches!(
+            decode_frame(&encode(&Message::Error("Esp(TimedOut)"))),
+            Ok((Command::Event(event), None)) if event.kind == "Esp"
+        ));
+        assert!(matches!(
+            decode_frame(&encode(&Message::Reading(SensorReading::Battery(f32::NAN)))),
+            Err(ParseError::InvalidValue(_))
+        ));
+        assert!(matches!(decode_frame(&[0xff, 0xff]), Err(ParseError::InvalidFrame(_))));
+        assert!(matches!(decode_frame(&[]), Err(ParseError::InvalidFrame(_))));
+    }
+
+    #[test]
+    fn signed_frames_are_decoded() {
+        let mut buffer = [0; 64];
+        let message = temperature(215);
+        buffer[..message.len()].copy_from_slice(&message);
+        let length = auth::sign_in_place(b"secret", 7, &mut buffer, message.len()).unwrap();
+
+        let (_, auth) = decode_frame(&buffer[..length]).unwrap();
+        let auth = auth.unwrap();
+        assert_eq!(auth.counter, 7);
+        assert_eq!(auth.message, message);
+        assert!(auth::verify(b"secret", 7, &auth.message, &auth.tag));
+
+        // A trailer that is cut short is not mistaken for an unsigned frame
+        assert!(matches!(decode_frame(&buffer[..length - 1]), Err(ParseError::InvalidAuth(_))));
+    }
+
+    #[test]
+    fn frames_are_split() {
+        let first = temperature(1);
+        let second = temperature(2);
+        let bytes = prefixed(&[&first, &[], &second]);
+        assert_eq!(split_frames(&bytes).unwrap(), vec!(&first[..], &[][..], &second[..]));
+        assert!(split_frames(&[]).unwrap().is_empty());
+
+        assert!(matches!(split_frames(&bytes[..bytes.len() - 1]), Err(ParseError::InvalidFrame(_))));
+        assert!(matches!(split_frames(&[0]), Err(ParseError::InvalidFrame(_))));
+    }
+
+    #[tokio::test]
+    async fn long_frames_are_skipped() {
+        let long = vec![0; MAX_FRAME_LENGTH + 1];
+        let short = temperature(1);
+        let bytes = prefixed(&[&long, &short]);
+        let mut stream = bytes.as_slice();
+
+        assert!(matches!(read_frame(&mut stream).await, Ok(Some(Err(ParseError::TooLong(_))))));
+        assert_eq!(read_frame(&mut stream).await.unwrap().unwrap().unwrap(), short);
+        assert!(read_frame(&mut stream).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn truncated_frames_are_errors() {
+        let bytes = prefixed(&[&temperature(1)]);
+        assert!(read_frame(&mut &bytes[..bytes.len() - 1]).await.is_err());
+
+        // Even if they are too long to be read
+        let bytes = prefixed(&[&vec![0; MAX_FRAME_LENGTH + 1]]);
+        assert!(read_frame(&mut &bytes[..bytes.len() - 1]).await.is_err());
+    }
+}
@@ -0,0 +1,697 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+
+use common::auth;
+
+use crate::types::{Command, Failure, Reply};
+use crate::config::{self, StationConfig};
+use crate::constants::{OPERATION_PREFIX, SEQ_PREFIX, BATCH_SEPARATOR, AUTH_SEPARATOR};
+use crate::influx::{self, InfluxError, Precision};
+use crate::events::{Event, Severity};
+use crate::identity::Identity;
+
+#[derive(Error, Debug, Clone)]
+pub enum ParseError {
+    #[error("Line is not valid UTF-8")]
+    InvalidUtf8,
+    #[error("Line is longer than {0} bytes")]
+    TooLong(usize),
+    #[error("Missing ':' in {0:?}")]
+    MissingSeparator(String),
+    #[error("Empty series name in {0:?}")]
+    EmptyName(String),
+    #[error("Failed to parse value {0:?}")]
+    InvalidValue(String),
+    #[error("Failed to parse timestamp {0:?}")]
+    InvalidTimestamp(String),
+    #[error("Unknown operation {0:?}")]
+    UnknownOperation(String),
+    #[error("Missing argument to operation {0:?}")]
+    MissingArgument(String),
+    #[error("Failed to parse sequence number {0:?}")]
+    InvalidSeq(String),
+    #[error("Invalid line protocol: {0}")]
+    Influx(#[from] InfluxError),
+    #[error("Invalid postcard frame: {0}")]
+    InvalidFrame(String),
+    #[error("Invalid authentication {0:?}")]
+    InvalidAuth(String),
+}
+
+impl ParseError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ParseError::InvalidUtf8 => "invalid_utf8",
+            ParseError::TooLong(_) => "too_long",
+            ParseError::MissingSeparator(_) => "missing_separator",
+            ParseError::EmptyName(_) => "empty_name",
+            ParseError::InvalidValue(_) => "invalid_value",
+            ParseError::InvalidTimestamp(_) => "invalid_timestamp",
+            ParseError::UnknownOperation(_) => "unknown_operation",
+            ParseError::MissingArgument(_) => "missing_argument",
+            ParseError::InvalidSeq(_) => "invalid_seq",
+            ParseError::Influx(_) => "influx",
+            ParseError::InvalidFrame(_) => "invalid_frame",
+            ParseError::InvalidAuth(_) => "invalid_auth",
+        }
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct ParseStats {
+    pub accepted: u64,
+    pub rejected: HashMap<&'static str, u64>,
+}
+
+impl ParseStats {
+    pub fn record<T>(&mut self, result: &Result<T, ParseError>) {
+        match result {
+            Ok(_) => self.accepted += 1,
+            Err(e) => *self.rejected.entry(e.kind()).or_insert(0) += 1,
+        }
+    }
+}
+
+pub type SharedParseStats = Arc<Mutex<ParseStats>>;
+
+// Splits the optional `#<seq> ` prefix that the sender uses to match up
+// replies with lines
+pub fn split_seq(line: &str) -> Result<(Option<u64>, &str), ParseError> {
+    let line = line.trim();
+    if line.starts_with(SEQ_PREFIX) {
+        let mut split = line[SEQ_PREFIX.len_utf8()..].splitn(2, char::is_whitespace);
+        let seq = split.next().unwrap_or("");
+        let seq = seq.parse::<u64>()
+            .map_err(|_| ParseError::InvalidSeq(seq.to_string()))?;
+        Ok((Some(seq), split.next().unwrap_or("").trim()))
+    }
+    else {
+        Ok((None, line))
+    }
+}
+
+// Parses the body of a line of the ingestion protocol. Lines are either an
+// operation prefixed by OPERATION_PREFIX, a batch of one or more readings
+// on the form `name:value[:timestamp]` separated by BATCH_SEPARATOR, or a line
+// of InfluxDB line protocol which is recognised by its `field=value` pairs
+pub fn parse_line(stations: &[StationConfig], line: &str) -> Result<Vec<Command>, ParseError> {
+    let line = line.trim();
+    if line.starts_with(OPERATION_PREFIX) {
+        Ok(vec!(parse_operation(&line[OPERATION_PREFIX.len_utf8()..])?))
+    }
+    else if line.contains('=') {
+        Ok(influx::parse_line(stations, line, Precision::Auto)?)
+    }
+    else {
+        line.split(BATCH_SEPARATOR).map(|reading| parse_reading(reading.trim())).collect()
+    }
+}
+
+// The outcome of committing a batch of commands
+pub struct Committed {
+    pub result: Result<Vec<String>, Failure>,
+    // Whether any of the commands may have been stored, quarantined values
+    // included. Only false if every command failed
+    pub stored: bool,
+}
+
+// Sends the commands and waits for all of them to be committed. The result has
+// the values that the commands answered with. Commands are committed one by
+// one and all of them run even if an earlier one fails, so a failed batch may
+// have been partly stored. A rejected value is only reported if nothing else
+// failed since there is no point in sending it again
+pub async fn commit(commands: Vec<Command>, tx: &Sender<Command>) -> Committed {
+    let mut failure = None;
+    let mut replies = vec!();
+    for command in commands {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        // Waits for room in the queue if the data handler is behind
+        if tx.send(Command::WithReply(Box::new(command), reply_tx)).await.is_err() {
+            failure = Some("command handler is not running".into());
+            break;
+        }
+        replies.push(reply_rx);
+    }
+
+    let mut values = vec!();
+    let mut rejected = None;
+    let mut stored = false;
+    for reply in replies {
+        let reply: Reply = match reply.await {
+            Ok(reply) => reply,
+            Err(_) => {
+                // The handler may have stopped after handling the command
+                stored = true;
+                failure.get_or_insert("command was dropped".into());
+                continue;
+            }
+        };
+        match reply {
+            Ok(value) => {
+                stored = true;
+                values.extend(value);
+            }
+            Err(Failure::Rejected(reason)) => {
+                stored = true;
+                rejected.get_or_insert(reason);
+            }
+            Err(e) => {
+                failure.get_or_insert(e);
+            }
+        }
+    }
+
+    let result = match (failure, rejected) {
+        (Some(failure), _) => Err(failure),
+        (None, Some(reason)) => Err(Failure::Rejected(reason)),
+        (None, None) => Ok(values),
+    };
+    Committed { result, stored }
+}
+
+// Counters of the stations with a key, used to reject replayed messages. They
+// are saved with the data so that replays are rejected after a restart too
+#[derive(Serialize, Deserialize, Default)]
+pub struct Counters {
+    // Last committed counter of each station
+    last: HashMap<String, u32>,
+    // Messages that are being committed, a copy that arrives in the meantime
+    // is rejected as well
+    #[serde(skip)]
+    pending: HashSet<(String, u32)>,
+}
+
+impl Counters {
+    fn reserve(&mut self, station: &str, counter: u32) -> Result<(), String> {
+        if let Some(last) = self.last.get(station).filter(|last| counter <= **last) {
+            return Err(format!("counter {} is not above {}", counter, last));
+        }
+        if !self.pending.insert((station.to_string(), counter)) {
+            return Err(format!("counter {} is already being handled", counter));
+        }
+        Ok(())
+    }
+
+    // Messages that may have been partly stored use up their counter so that
+    // they can not be replayed. A station can send one that failed completely again
+    fn finish(&mut self, station: &str, counter: u32, stored: bool) {
+        self.pending.remove(&(station.to_string(), counter));
+        if stored {
+            let last = self.last.entry(station.to_string()).or_insert(counter);
+            *last = (*last).max(counter);
+        }
+    }
+}
+
+pub type SharedCounters = Arc<Mutex<Counters>>;
+
+// Everything needed to execute what the stations send
+#[derive(Clone)]
+pub struct Ingest {
+    pub stations: Arc<Vec<StationConfig>>,
+    pub tx: Sender<Command>,
+    pub stats: SharedParseStats,
+    pub counters: SharedCounters,
+}
+
+impl Ingest {
+    // Sends the commands to `tx` and starts without any counters
+    #[cfg(test)]
+    pub fn for_test(stations: Vec<StationConfig>, tx: Sender<Command>) -> Self {
+        Self {
+            stations: Arc::new(stations),
+            tx,
+            stats: Default::default(),
+            counters: Default::default(),
+        }
+    }
+}
+
+// The counter and MAC that a message was sent with, and the bytes that they cover
+pub struct Auth {
+    pub counter: u32,
+    pub tag: Vec<u8>,
+    pub message: Vec<u8>,
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair).ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect()
+}
+
+// Splits the optional `|<counter>:<hex MAC>` suffix of authenticated lines.
+// The MAC covers the body before the separator
+pub fn split_auth(body: &str) -> Result<(&str, Option<Auth>), ParseError> {
+    let mut split = body.rsplitn(2, AUTH_SEPARATOR);
+    let (suffix, body) = match (split.next(), split.next()) {
+        (Some(suffix), Some(body)) => (suffix.trim(), body.trim()),
+        _ => return Ok((body, None))
+    };
+
+    let mut suffix_split = suffix.splitn(2, ':');
+    let counter = suffix_split.next().and_then(|counter| counter.parse::<u32>().ok());
+    let tag = suffix_split.next().and_then(parse_hex);
+    match (counter, tag) {
+        (Some(counter), Some(tag)) => {
+            Ok((body, Some(Auth { counter, tag, message: body.as_bytes().to_vec() })))
+        }
+        _ => Err(ParseError::InvalidAuth(suffix.to_string()))
+    }
+}
+
+// Names of the series that a command changes
+fn touched_series(command: &Command) -> Vec<&str> {
+    match command {
+        Command::Reset(name)
+        | Command::AddDatapoint(name, _, _)
+        | Command::DeleteRange(name, _, _)
+        | Command::SetMetadata(name, _, _) => vec!(name),
+        Command::Rename(from, to) => vec!(from, to),
+        _ => vec!()
+    }
+}
+
+// What is known about the other end of a connection
+pub struct Session {
+    // Where the messages come from, like the IP address of the sender. Used to
+    // attribute events until the station identifies itself, so it should not
+    // change between connections from the same station, unlike the port does
+    pub source: String,
+    pub identity: Option<Identity>,
+    // Set when the hello was rejected, everything else from the session is then refused
+    rejected: Option<String>,
+}
+
+impl Session {
+    pub fn new(source: impl Into<String>) -> Self {
+        Self { source: source.into(), identity: None, rejected: None }
+    }
+
+    // Once a station has identified itself, the series it sends are named
+    // relative to the station
+    fn attribute(&self, commands: &mut [Command], station: Option<&str>, stations: &[StationConfig]) {
+        for command in commands {
+            match (command, station) {
+                (Command::AddDatapoint(name, _, _), Some(station)) => {
+                    *name = config::series_name(stations, station, name);
+                }
+                (Command::Event(event), _) => {
+                    event.station.get_or_insert_with(|| station.unwrap_or(&self.source).to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Checks the MAC of a message if `station` has a key. Returns the station
+    // that the message was verified to be from and the counter it was sent with
+    fn authenticate(
+        &self,
+        station: Option<&str>,
+        auth: Option<Auth>,
+        ingest: &Ingest
+    ) -> Result<Option<(String, u32)>, String> {
+        let (station, key) = match station.and_then(|name| ingest.stations.iter().find(|s| s.name == name)) {
+            Some(StationConfig { name, key: Some(key), .. }) => (name, key),
+            _ => return Ok(None)
+        };
+
+        let auth = auth.ok_or_else(|| format!("{} has to authenticate its messages", station))?;
+        if !auth::verify(key.as_bytes(), auth.counter, &auth.message, &auth.tag) {
+            warn!("Invalid MAC from {} claiming to be {}", self.source, station);
+            return Err("invalid MAC".into());
+        }
+        Ok(Some((station.clone(), auth.counter)))
+    }
+
+    // Executes commands received on this session, see `commit`. `auth` is
+    // required if the station has a key
+    pub async fn execute(
+        &mut self,
+        mut commands: Vec<Command>,
+        auth: Option<Auth>,
+        ingest: &Ingest
+    ) -> Result<Vec<String>, Failure> {
+        if let Some(reason) = &self.rejected {
+            return Err(reason.as_str().into());
+        }
+
+        let hello = commands.iter()
+            .find_map(|command| match command {
+                Command::Hello(identity) => Some(identity.clone()),
+                _ => None
+            });
+        if let Some(Err(e)) = hello.as_ref().map(Identity::check_protocol) {
+            warn!("Rejecting {}: {}", self.source, e);
+            self.rejected = Some(e.to_string());
+            return Err(e.to_string().into());
+        }
+
+        let claimed = hello.as_ref().or(self.identity.as_ref()).map(|i| i.station.as_str());
+        let authenticated = self.authenticate(claimed, auth, ingest)?;
+        let station = authenticated.as_ref().map(|(station, _)| station.as_str());
+
+        // Includes commands sent along with the hello
+        self.attribute(&mut commands, claimed, &ingest.stations);
+
+        // Series of stations with a key may only be changed by that station
+        for name in commands.iter().flat_map(touched_series) {
+            if let (Some(owner), _) = config::split_series_name(&ingest.stations, name) {
+                let protected = ingest.stations.iter().any(|s| s.name == owner && s.key.is_some());
+                if protected && station != Some(owner) {
+                    return Err(format!("{} can only be changed by {}", name, owner).into());
+                }
+            }
+        }
+
+        if let Some((station, counter)) = &authenticated {
+            if let Err(e) = ingest.counters.lock().unwrap().reserve(station, *counter) {
+                warn!("Replayed message from {} claiming to be {}", self.source, station);
+                return Err(e.into());
+            }
+        }
+        let committed = commit(commands, &ingest.tx).await;
+        if let Some((station, counter)) = &authenticated {
+            ingest.counters.lock().unwrap().finish(station, *counter, committed.stored);
+        }
+
+        let values = committed.result?;
+        if let Some(identity) = hello {
+            info!("{} is station {}", self.source, identity.station);
+            self.identity = Some(identity);
+        }
+        Ok(values)
+    }
+}
+
+// Parses and executes a line, returning the reply to send back. `default_seq`
+// is used in the reply if the line has no sequence number. Blank lines get no
+// reply. The reply is `OK`, `ERR` if the line can be sent again or `REJ` if a
+// value was quarantined and sending it again would not help
+pub async fn handle_line(
+    line: &str,
+    default_seq: u64,
+    session: &mut Session,
+    ingest: &Ingest
+) -> Option<String> {
+    if line.trim().is_empty() {
+        return None;
+    }
+    info!("Got message: {}", line.trim());
+
+    let parsed = split_seq(line)
+        .and_then(|(seq, body)| {
+            let (body, auth) = split_auth(body)?;
+            Ok((seq.unwrap_or(default_seq), parse_line(&ingest.stations, body)?, auth))
+        });
+    ingest.stats.lock().unwrap().record(&parsed);
+
+    Some(match parsed {
+        Ok((seq, commands, auth)) => match session.execute(commands, auth, ingest).await {
+            Ok(values) if values.is_empty() => format!("OK {}", seq),
+            Ok(values) => format!("OK {} {}", seq, values.join(" ")),
+            Err(Failure::Rejected(reason)) => format!("REJ {} {}", seq, reason),
+            Err(Failure::Error(reason)) => format!("ERR {} {}", seq, reason),
+        },
+        Err(e) => {
+            warn!("Ignoring bad line: {}", e);
+            // The sequence number may be what failed to parse
+            let seq = split_seq(line).ok().and_then(|(seq, _)| seq).unwrap_or(default_seq);
+            format!("ERR {} {}", seq, e)
+        }
+    })
+}
+
+fn parse_reading(line: &str) -> Result<Command, ParseError> {
+    let mut split = line.split(':');
+
+    let name = split.next().unwrap_or("").trim();
+    let value = split.next()
+        .ok_or_else(|| ParseError::MissingSeparator(line.to_string()))?;
+    if name.is_empty() {
+        return Err(ParseError::EmptyName(line.to_string()));
+    }
+
+    let value = value.trim().parse::<f32>().ok()
+        .filter(|value| value.is_finite())
+        .ok_or_else(|| ParseError::InvalidValue(value.to_string()))?;
+
+    let timestamp = split.next()
+        .map(|timestamp| {
+            timestamp.trim().parse::<f64>().ok()
+                .filter(|timestamp| timestamp.is_finite())
+                .ok_or_else(|| ParseError::InvalidTimestamp(timestamp.to_string()))
+        })
+        .transpose()?;
+
+    Ok(Command::AddDatapoint(name.to_string(), value, timestamp))
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<Option<f64>, ParseError> {
+    let timestamp = timestamp.trim();
+    if timestamp.is_empty() {
+        return Ok(None);
+    }
+    timestamp.parse::<f64>().ok()
+        .filter(|timestamp| timestamp.is_finite())
+        .map(Some)
+        .ok_or_else(|| ParseError::InvalidTimestamp(timestamp.to_string()))
+}
+
+// Operations are on the form `operation[:argument]*`. Arguments that are left out
+// at the end are empty
+fn parse_operation(line: &str) -> Result<Command, ParseError> {
+    let mut split = line.splitn(2, ':');
+    let operation = split.next().unwrap_or("").trim().to_lowercase();
+    let rest = split.next().unwrap_or("");
+
+    let arguments = rest.split(':').map(str::trim).collect::<Vec<_>>();
+    let argument = |index: usize| arguments.get(index).cloned().unwrap_or("");
+    let required = |index: usize| {
+        Some(argument(index))
+            .filter(|argument| !argument.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| ParseError::MissingArgument(operation.clone()))
+    };
+
+    match operation.as_str() {
+        "reset" => Ok(Command::Reset(required(0)?)),
+        "rename" => Ok(Command::Rename(required(0)?, required(1)?)),
+        "delete" => Ok(Command::DeleteRange(
+            required(0)?,
+            parse_timestamp(argument(1))?,
+            parse_timestamp(argument(2))?
+        )),
+        "list" => Ok(Command::ListSeries),
+        "snapshot" => Ok(Command::Snapshot),
+        // The value is everything after the key so that it may contain ':'
+        "meta" => {
+            let mut split = rest.splitn(3, ':');
+            let name = split.next().map(str::trim).filter(|name| !name.is_empty());
+            let key = split.next().map(str::trim).filter(|key| !key.is_empty());
+            match (name, key) {
+                (Some(name), Some(key)) => Ok(Command::SetMetadata(
+                    name.to_string(),
+                    key.to_string(),
+                    split.next().unwrap_or("").trim().to_string()
+                )),
+                _ => Err(ParseError::MissingArgument(operation.clone()))
+            }
+        }
+        "time" => Ok(Command::Time),
+        "hello" => {
+            let protocol_version = required(2)?;
+            let boot_counter = required(3)?;
+            Ok(Command::Hello(Identity {
+                station: required(0)?,
+                firmware_version: required(1)?,
+                protocol_version: protocol_version.parse()
+                    .map_err(|_| ParseError::InvalidValue(protocol_version))?,
+                boot_counter: boot_counter.parse()
+                    .map_err(|_| ParseError::InvalidValue(boot_counter))?,
+            }))
+        }
+        // Sent by stmhardware as `;Fatal: <error>` before it stops
+        "fatal" => Ok(Command::Event(Event::new(Severity::Fatal, rest.trim()))),
+        _ => Err(ParseError::UnknownOperation(operation.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyed_ingest() -> Ingest {
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+        let station = StationConfig {
+            name: "garden".into(),
+            series: vec!("temperature".into()),
+            expected_interval: 300,
+            key: Some("secret".into()),
+        };
+        Ingest::for_test(vec!(station), tx)
+    }
+
+    // Answers commands in order like the data handler would
+    fn answering(replies: Vec<Reply>) -> Sender<Command> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        tokio::spawn(async move {
+            for reply in replies {
+                if let Some(Command::WithReply(_, reply_tx)) = rx.recv().await {
+                    let _ = reply_tx.send(reply);
+                }
+            }
+        });
+        tx
+    }
+
+    fn reading(value: f32) -> Command {
+        Command::AddDatapoint("temperature".into(), value, None)
+    }
+
+    #[tokio::test]
+    async fn partly_failed_batches_are_stored() {
+        let tx = answering(vec!(Ok(None), Err(Failure::Error("disk full".into()))));
+        let committed = commit(vec!(reading(1.), reading(2.)), &tx).await;
+        assert_eq!(committed.result, Err(Failure::Error("disk full".into())));
+        assert!(committed.stored);
+    }
+
+    #[tokio::test]
+    async fn rejected_values_are_stored() {
+        let tx = answering(vec!(Err(Failure::Rejected("spike".into())), Ok(None)));
+        let committed = commit(vec!(reading(1.), reading(2.)), &tx).await;
+        assert_eq!(committed.result, Err(Failure::Rejected("spike".into())));
+        assert!(committed.stored);
+    }
+
+    #[tokio::test]
+    async fn failed_batches_are_not_stored() {
+        let tx = answering(vec!(Err(Failure::Error("disk full".into()))));
+        let committed = commit(vec!(reading(1.)), &tx).await;
+        assert!(committed.result.is_err());
+        assert!(!committed.stored);
+    }
+
+    #[tokio::test]
+    async fn partly_stored_batches_can_not_be_replayed() {
+        let mut ingest = keyed_ingest();
+        ingest.tx = answering(vec!(Ok(None), Ok(None), Err(Failure::Error("disk full".into()))));
+        let hello = || Command::Hello(Identity {
+            station: "garden".into(),
+            firmware_version: "1.0".into(),
+            protocol_version: common::PROTOCOL_VERSION,
+            boot_counter: 1,
+        });
+        let message = b"signed batch".to_vec();
+        let auth = || Some(Auth {
+            counter: 5,
+            tag: auth::sign(b"secret", 5, &message).to_vec(),
+            message: message.clone(),
+        });
+
+        let mut session = Session::new("127.0.0.1");
+        let result = session.execute(vec!(hello(), reading(1.), reading(2.)), auth(), &ingest).await;
+        assert_eq!(result, Err(Failure::Error("disk full".into())));
+
+        let replayed = session.execute(vec!(hello(), reading(1.), reading(2.)), auth(), &ingest).await;
+        assert_eq!(replayed, Err(Failure::Error("counter 5 is not above 5".into())));
+    }
+
+    #[test]
+    fn readings() {
+        assert!(matches!(
+            parse_reading("temperature: 21.5 :1600000000"),
+            Ok(Command::AddDatapoint(name, value, Some(t))) if name == "temperature" && value == 21.5 && t == 1600000000.
+        ));
+        assert!(matches!(parse_reading("temperature"), Err(ParseError::MissingSeparator(_))));
+        assert!(matches!(parse_reading(" :21.5"), Err(ParseError::EmptyName(_))));
+        assert!(matches!(parse_reading("temperature:warm"), Err(ParseError::InvalidValue(_))));
+        assert!(matches!(parse_reading("temperature:NaN"), Err(ParseError::InvalidValue(_))));
+        assert!(matches!(parse_reading("temperature:21.5:yesterday"), Err(ParseError::InvalidTimestamp(_))));
+        assert!(matches!(parse_reading("temperature:21.5:inf"), Err(ParseError::InvalidTimestamp(_))));
+    }
+
+    #[test]
+    fn operations() {
+        assert!(matches!(parse_operation("RESET:temperature"), Ok(Command::Reset(name)) if name == "temperature"));
+        assert!(matches!(parse_operation("reset"), Err(ParseError::MissingArgument(_))));
+        assert!(matches!(parse_operation("rename:a"), Err(ParseError::MissingArgument(_))));
+        assert!(matches!(
+            parse_operation("delete:temperature::100"),
+            Ok(Command::DeleteRange(_, None, Some(t))) if t == 100.
+        ));
+        assert!(matches!(parse_operation("delete:temperature:soon"), Err(ParseError::InvalidTimestamp(_))));
+        assert!(matches!(
+            parse_operation("meta:temperature:url:http://example.com"),
+            Ok(Command::SetMetadata(_, key, value)) if key == "url" && value == "http://example.com"
+        ));
+        assert!(matches!(parse_operation("meta:temperature"), Err(ParseError::MissingArgument(_))));
+        assert!(matches!(parse_operation("hello:garden:1.0:x:3"), Err(ParseError::InvalidValue(_))));
+        assert!(matches!(parse_operation("hello:garden:1.0:1"), Err(ParseError::MissingArgument(_))));
+        assert!(matches!(parse_operation("explode"), Err(ParseError::UnknownOperation(_))));
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        assert_eq!(split_seq("#12 temperature:21.5").unwrap(), (Some(12), "temperature:21.5"));
+        assert_eq!(split_seq("temperature:21.5").unwrap(), (None, "temperature:21.5"));
+        assert!(matches!(split_seq("#twelve temperature:21.5"), Err(ParseError::InvalidSeq(_))));
+        assert!(matches!(split_seq("#-1 temperature:21.5"), Err(ParseError::InvalidSeq(_))));
+    }
+
+    #[test]
+    fn committed_counters_are_used_up() {
+        let mut counters = Counters::default();
+        counters.reserve("garden", 5).unwrap();
+        counters.finish("garden", 5, true);
+        assert!(counters.reserve("garden", 5).is_err());
+        assert!(counters.reserve("garden", 4).is_err());
+        assert!(counters.reserve("garden", 6).is_ok());
+        // Other stations count on their own
+        assert!(counters.reserve("roof", 1).is_ok());
+    }
+
+    #[test]
+    fn failed_commits_keep_their_counter() {
+        let mut counters = Counters::default();
+        counters.reserve("garden", 5).unwrap();
+        // A copy is rejected while the first one is being committed
+        assert!(counters.reserve("garden", 5).is_err());
+        counters.finish("garden", 5, false);
+        assert!(counters.reserve("garden", 5).is_ok());
+    }
+
+    #[test]
+    fn counters_survive_saving() {
+        let mut counters = Counters::default();
+        counters.reserve("garden", 5).unwrap();
+        counters.finish("garden", 5, true);
+        counters.reserve("garden", 6).unwrap();
+
+        let saved = serde_json::to_string(&counters).unwrap();
+        let mut loaded = serde_json::from_str::<Counters>(&saved).unwrap();
+        assert!(loaded.reserve("garden", 5).is_err());
+        // Pending messages were not committed
+        assert!(loaded.reserve("garden", 6).is_ok());
+    }
+
+    #[tokio::test]
+    async fn unsigned_writes_to_keyed_series_are_rejected() {
+        let ingest = keyed_ingest();
+        let commands = vec!(Command::AddDatapoint("temperature".into(), 20., None));
+        let result = Session::new("mqtt").execute(commands, None, &ingest).await;
+        assert_eq!(result, Err(Failure::Error("temperature can only be changed by garden".into())));
+    }
+}
@@ -0,0 +1,114 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use tokio::signal;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use color_anyhow::anyhow::Context;
+
+use crate::alerts::SharedAlertEngine;
+use crate::config::{self, Config, SharedSeriesConfig};
+use crate::error::Result;
+use crate::health::SharedHealthTracker;
+use crate::notifier::{self, Notifiers, SharedNotifiers};
+
+// How often the config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Where the settings that can change while running are applied
+pub struct Reloader {
+    pub path: PathBuf,
+    // Overrides from the command line, they win over the file
+    pub data: Option<PathBuf>,
+    pub log_level: Option<log::LevelFilter>,
+    pub series: SharedSeriesConfig,
+    pub alerts: SharedAlertEngine,
+    pub notifiers: SharedNotifiers,
+    pub health: SharedHealthTracker,
+}
+
+pub fn log_level(config: &Config, cli: Option<log::LevelFilter>) -> log::LevelFilter {
+    cli.or(config.log_level).unwrap_or(log::LevelFilter::Info)
+}
+
+fn modified(reloader: &Reloader) -> Option<SystemTime> {
+    fs::metadata(&reloader.path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Reads and validates the config the same way as on startup
+fn load(reloader: &Reloader) -> Result<(Config, Notifiers)> {
+    let mut config = config::read_config(&reloader.path)
+        .with_context(|| format!("Failed to read {:?}", reloader.path))?;
+    if let Some(data) = &reloader.data {
+        config.log_filename = data.clone();
+    }
+
+    let notifiers = notifier::build_notifiers(&config.notifiers)
+        .context("Failed to set up notifiers")?;
+    notifier::warn_unknown_notifiers(&config.alerts, &notifiers);
+    Ok((config, notifiers))
+}
+
+fn warn_about_restart(old: &Config, new: &Config) {
+    let changed = [
+        ("http_address", old.http_address != new.http_address || old.http_port != new.http_port),
+        ("sources", old.sources != new.sources),
+        ("log_filename", old.log_filename != new.log_filename),
+        ("metadata_filename", old.metadata_filename != new.metadata_filename),
+        ("events_filename", old.events_filename != new.events_filename),
+        ("counters_filename", old.counters_filename != new.counters_filename),
+        ("stations", old.stations != new.stations),
+        ("shutdown_timeout", old.shutdown_timeout != new.shutdown_timeout),
+    ];
+    for (name, _) in changed.iter().filter(|(_, changed)| *changed) {
+        warn!("{} changed, restart the server to apply it", name);
+    }
+}
+
+fn apply(reloader: &Reloader, config: &Config, notifiers: Notifiers) {
+    *reloader.series.write().unwrap() = config.series.clone();
+    *reloader.notifiers.lock().unwrap() = notifiers;
+    reloader.health.lock().unwrap().set_series(config.series.clone());
+    reloader.alerts.lock().unwrap().set_rules(config.alerts.clone());
+
+    let level = log_level(config, reloader.log_level);
+    if level != log::max_level() {
+        info!("Log level set to {}", level);
+        log::set_max_level(level);
+    }
+}
+
+// Reloads the config on SIGHUP or when the file changes. Series settings, alert
+// rules, notifiers and the log level are applied right away, a config that fails to load
+// leaves the running one in place
+pub fn run_reloader(reloader: Reloader, mut current: Config) -> Result<JoinHandle<()>> {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+
+    Ok(tokio::spawn(async move {
+        let mut last_modified = modified(&reloader);
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("Received SIGHUP, reloading {:?}", reloader.path),
+                _ = time::sleep(POLL_INTERVAL) => {
+                    if modified(&reloader) == last_modified {
+                        continue;
+                    }
+                    info!("{:?} changed, reloading", reloader.path);
+                }
+            }
+            last_modified = modified(&reloader);
+
+            match load(&reloader) {
+                Ok((config, notifiers)) => {
+                    warn_about_restart(&current, &config);
+                    apply(&reloader, &config, notifiers);
+                    current = config;
+                    info!("Config reloaded");
+                }
+                Err(e) => error!("Keeping the running config: {:#}", e),
+            }
+        }
+    }))
+}
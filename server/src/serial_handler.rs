@@ -0,0 +1,240 @@
+use std::time::Duration;
+
+use tokio::io::BufReader;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use crate::error::Result;
+use crate::postcard_handler;
+use crate::protocol::{self, Ingest, ParseError, Session};
+use crate::source::SourceState;
+use crate::tcp_handler;
+
+// Time to wait before opening the device again after it failed or disappeared
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    // `name:value` lines like on TCP
+    #[default]
+    Lines,
+    // COBS encoded postcard common::Message frames, each ending with a zero
+    Cobs,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct SerialConfig {
+    pub path: String,
+    #[serde(default = "default_baud_rate")]
+    pub baud_rate: u32,
+    #[serde(default)]
+    pub framing: Framing,
+}
+
+fn default_baud_rate() -> u32 { 115200 }
+
+fn open(config: &SerialConfig) -> tokio_serial::Result<SerialStream> {
+    tokio_serial::new(&config.path, config.baud_rate).open_native_async()
+}
+
+async fn handle_line(line: &[u8], line_number: u64, session: &mut Session, ingest: &Ingest) {
+    let reply = match std::str::from_utf8(line) {
+        Ok(line) => protocol::handle_line(line, line_number, session, ingest).await,
+        Err(_) => {
+            ingest.stats.lock().unwrap().record::<()>(&Err(ParseError::InvalidUtf8));
+            Some(format!("ERR {} {}", line_number, ParseError::InvalidUtf8))
+        }
+    };
+    if let Some(reply) = reply.filter(|reply| !reply.starts_with("OK")) {
+        warn!("Serial line from {} failed: {}", session.source, reply);
+    }
+}
+
+async fn handle_cobs_frame(frame: &mut [u8], session: &mut Session, ingest: &Ingest) {
+    // Senders may start frames with a zero as well, which gives empty frames
+    if frame.is_empty() {
+        return;
+    }
+    match cobs::decode_in_place(frame) {
+        Ok(length) => postcard_handler::handle_frame(&frame[..length], session, ingest).await,
+        Err(_) => {
+            warn!("Ignoring frame with invalid COBS encoding from {}", session.source);
+            let error = ParseError::InvalidFrame("invalid COBS encoding".into());
+            ingest.stats.lock().unwrap().record::<()>(&Err(error));
+        }
+    }
+}
+
+// Nothing is written back since a device that never reads would eventually
+// block us, so errors are only logged
+async fn read_device(stream: SerialStream, config: &SerialConfig, ingest: &Ingest) -> Result<()> {
+    let mut session = Session::new(config.path.clone());
+    let mut reader = BufReader::new(stream);
+
+    let delimiter = match config.framing {
+        Framing::Lines => b'\n',
+        Framing::Cobs => 0,
+    };
+    let mut frame_number = 0;
+    while let Some(frame) = tcp_handler::read_frame(&mut reader, delimiter).await? {
+        frame_number += 1;
+        match (frame, config.framing) {
+            (Ok(line), Framing::Lines) => handle_line(&line, frame_number, &mut session, ingest).await,
+            (Ok(mut frame), Framing::Cobs) => handle_cobs_frame(&mut frame, &mut session, ingest).await,
+            (Err(e), _) => {
+                warn!("Ignoring bad frame from {}: {}", config.path, e);
+                ingest.stats.lock().unwrap().record::<()>(&Err(e));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Reads from a serial device, for example a station connected over USB. The
+// device is opened again whenever it fails or disappears
+pub fn run_serial_handler(config: SerialConfig, ingest: Ingest, state: SourceState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // Only the first of a series of failed attempts is logged as a warning
+        let mut failing = false;
+        loop {
+            state.set_connecting(true);
+            match open(&config) {
+                Ok(stream) => {
+                    info!("Opened serial device {}", config.path);
+                    failing = false;
+                    state.set_connecting(false);
+                    match read_device(stream, &config, &ingest).await {
+                        Ok(()) => warn!("Serial device {} was closed, reopening", config.path),
+                        Err(e) => warn!("Serial device {} failed: {:#}, reopening", config.path, e),
+                    }
+                }
+                Err(e) if failing => debug!("Failed to open serial device {}: {}", config.path, e),
+                Err(e) => {
+                    warn!("Failed to open serial device {}: {}, retrying", config.path, e);
+                    failing = true;
+                }
+            }
+            sleep(RECONNECT_DELAY).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+
+    use common::{DeciCelcius, Message, SensorReading};
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::mpsc::Receiver;
+    use tokio::time::timeout;
+    use tokio_serial::SerialPort;
+
+    use crate::types::Command;
+
+    // Long enough for the reader to notice a closed pty and open it again
+    const TIMEOUT: Duration = Duration::from_secs(15);
+    const WRITE_INTERVAL: Duration = Duration::from_millis(200);
+
+    // A pty that the reader opens through a symlink, so that it can be
+    // replaced by a new one like a device that is plugged in again
+    struct Pty {
+        master: SerialStream,
+        link: PathBuf,
+    }
+
+    impl Pty {
+        fn open(link: &Path) -> Pty {
+            let (master, slave) = SerialStream::pair().unwrap();
+            let _ = std::fs::remove_file(link);
+            symlink(slave.name().unwrap(), link).unwrap();
+            Pty { master, link: link.to_path_buf() }
+        }
+    }
+
+    impl Drop for Pty {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.link);
+        }
+    }
+
+    fn start(pty: &Pty, framing: Framing) -> (JoinHandle<()>, Receiver<Command>) {
+        let (tx, rx) = tokio::sync::mpsc::channel(8);
+        let ingest = Ingest::for_test(vec!(), tx);
+        let config = SerialConfig {
+            path: pty.link.to_string_lossy().into(),
+            baud_rate: default_baud_rate(),
+            framing,
+        };
+        (run_serial_handler(config, ingest, SourceState::default()), rx)
+    }
+
+    // Writes `bytes` until the reader stores `expected`. The reader may not
+    // have opened the pty yet, and bytes written before that can be lost
+    async fn expect_datapoint(pty: &mut Pty, bytes: &[u8], rx: &mut Receiver<Command>, expected: (&str, f32)) {
+        let receive = async {
+            loop {
+                match rx.recv().await.unwrap() {
+                    Command::WithReply(command, reply) => {
+                        let _ = reply.send(Ok(None));
+                        if let Command::AddDatapoint(name, value, _) = *command {
+                            if (name.as_str(), value) == expected {
+                                return;
+                            }
+                        }
+                    }
+                    _ => panic!("Expected a command with a reply"),
+                }
+            }
+        };
+        tokio::pin!(receive);
+
+        timeout(TIMEOUT, async {
+            loop {
+                pty.master.write_all(bytes).await.unwrap();
+                tokio::select! {
+                    _ = &mut receive => return,
+                    _ = sleep(WRITE_INTERVAL) => {}
+                }
+            }
+        }).await.expect("The datapoint was not received");
+    }
+
+    fn link(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("weather-test-{}-{}", std::process::id(), name))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lines_are_read_until_the_pty_is_reopened() {
+        let link = link("lines");
+        let mut pty = Pty::open(&link);
+        let (reader, mut rx) = start(&pty, Framing::Lines);
+
+        expect_datapoint(&mut pty, b"pty:1.5\n", &mut rx, ("pty", 1.5)).await;
+
+        // Unplugging the device closes the pty and a new one shows up at the same path
+        drop(pty);
+        let mut pty = Pty::open(&link);
+        expect_datapoint(&mut pty, b"pty:2.5\n", &mut rx, ("pty", 2.5)).await;
+
+        reader.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cobs_frames_are_read() {
+        let mut pty = Pty::open(&link("cobs"));
+        let (reader, mut rx) = start(&pty, Framing::Cobs);
+
+        let mut buffer = [0; 16];
+        let message = Message::Reading(SensorReading::Temperature(DeciCelcius(215)));
+        let mut frame = cobs::encode_vec(postcard::to_slice(&message, &mut buffer).unwrap());
+        frame.push(0);
+        expect_datapoint(&mut pty, &frame, &mut rx, ("temperature", 21.5)).await;
+
+        reader.abort();
+    }
+}
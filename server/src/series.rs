@@ -0,0 +1,125 @@
+use std::cmp::Ordering;
+
+use crate::types::Datapoint;
+
+// What to do with a datapoint that has the same timestamp as a stored one
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    // Retransmissions are ignored
+    #[default]
+    KeepFirst,
+    KeepLast,
+    // The stored value becomes the mean of all values with its timestamp
+    Average,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Insertion {
+    Inserted,
+    // The stored value was changed by the duplicate policy
+    Replaced,
+    Ignored,
+}
+
+// Index where a point with `timestamp` belongs. Equal timestamps are found
+// through `Ok`, like binary_search
+pub fn position(data: &[Datapoint], timestamp: f64) -> Result<usize, usize> {
+    data.binary_search_by(|point| {
+        point.timestamp.partial_cmp(&timestamp).unwrap_or(Ordering::Less)
+    })
+}
+
+// Inserts a point while keeping the series sorted by timestamp
+pub fn insert(data: &mut Vec<Datapoint>, point: Datapoint, policy: DuplicatePolicy) -> Insertion {
+    match position(data, point.timestamp) {
+        Ok(index) => match policy {
+            DuplicatePolicy::KeepFirst => Insertion::Ignored,
+            DuplicatePolicy::KeepLast => {
+                data[index] = point;
+                Insertion::Replaced
+            }
+            DuplicatePolicy::Average => {
+                let stored = &mut data[index];
+                let samples = stored.samples + point.samples;
+                stored.value = ((stored.value as f64 * stored.samples as f64
+                    + point.value as f64 * point.samples as f64) / samples as f64) as f32;
+                stored.samples = samples;
+                Insertion::Replaced
+            }
+        },
+        Err(index) => {
+            data.insert(index, point);
+            Insertion::Inserted
+        }
+    }
+}
+
+// Sorts data that was stored before inserts kept series sorted. Points with
+// the same timestamp are kept in their original order
+pub fn sort(data: &mut [Datapoint]) {
+    data.sort_by(|a, b| a.timestamp.partial_cmp(&b.timestamp).unwrap_or(Ordering::Equal));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert_all(points: &[(f64, f32)], policy: DuplicatePolicy) -> Vec<Datapoint> {
+        let mut data = vec!();
+        for (timestamp, value) in points {
+            insert(&mut data, Datapoint::new(*timestamp, *value), policy);
+        }
+        data
+    }
+
+    fn values(data: &[Datapoint]) -> Vec<(f64, f32)> {
+        data.iter().map(|point| (point.timestamp, point.value)).collect()
+    }
+
+    #[test]
+    fn inserts_keep_the_series_sorted() {
+        let data = insert_all(&[(3., 30.), (1., 10.), (4., 40.), (2., 20.), (0., 0.)], DuplicatePolicy::KeepFirst);
+        assert_eq!(values(&data), vec!((0., 0.), (1., 10.), (2., 20.), (3., 30.), (4., 40.)));
+    }
+
+    #[test]
+    fn insertion_outcome() {
+        let mut data = vec!(Datapoint::new(1., 10.));
+        assert_eq!(insert(&mut data, Datapoint::new(2., 20.), DuplicatePolicy::KeepFirst), Insertion::Inserted);
+        assert_eq!(insert(&mut data, Datapoint::new(1., 11.), DuplicatePolicy::KeepFirst), Insertion::Ignored);
+        assert_eq!(insert(&mut data, Datapoint::new(1., 11.), DuplicatePolicy::KeepLast), Insertion::Replaced);
+        assert_eq!(insert(&mut data, Datapoint::new(1., 11.), DuplicatePolicy::Average), Insertion::Replaced);
+    }
+
+    #[test]
+    fn keep_first() {
+        let data = insert_all(&[(1., 10.), (2., 20.), (1., 11.), (1., 12.)], DuplicatePolicy::KeepFirst);
+        assert_eq!(values(&data), vec!((1., 10.), (2., 20.)));
+    }
+
+    #[test]
+    fn keep_last() {
+        let data = insert_all(&[(1., 10.), (2., 20.), (1., 11.), (1., 12.)], DuplicatePolicy::KeepLast);
+        assert_eq!(values(&data), vec!((1., 12.), (2., 20.)));
+    }
+
+    #[test]
+    fn average_is_the_mean_of_all_duplicates() {
+        let data = insert_all(&[(1., 10.), (2., 20.), (1., 10.), (1., 40.)], DuplicatePolicy::Average);
+        assert_eq!(values(&data), vec!((1., 20.), (2., 20.)));
+        assert_eq!(data[0].samples, 3);
+        assert_eq!(data[1].samples, 1);
+    }
+
+    #[test]
+    fn averages_are_saved_with_their_samples() {
+        let data = insert_all(&[(1., 10.), (1., 40.), (2., 20.)], DuplicatePolicy::Average);
+        let saved = serde_json::to_string(&data).unwrap();
+        assert_eq!(saved, r#"[{"timestamp":1.0,"value":25.0,"samples":2},{"timestamp":2.0,"value":20.0}]"#);
+
+        let mut loaded = serde_json::from_str::<Vec<Datapoint>>(&saved).unwrap();
+        insert(&mut loaded, Datapoint::new(1., 100.), DuplicatePolicy::Average);
+        assert_eq!(values(&loaded), vec!((1., 50.), (2., 20.)));
+    }
+}
@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+use tokio::signal;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use color_anyhow::anyhow::anyhow;
+
+use crate::error::Result;
+use crate::types::Command;
+
+// Waits for Ctrl-C or SIGTERM
+pub async fn wait_for_signal() -> Result<()> {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        result = signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+// Lets the command handler finish what is queued and save everything. Gives
+// up after `limit` so that a stuck handler does not keep the server running
+pub async fn drain(tx: &Sender<Command>, limit: Duration) -> Result<()> {
+    let (done_tx, done_rx) = oneshot::channel();
+    let drained = timeout(limit, async {
+        tx.send(Command::Shutdown(done_tx)).await
+            .map_err(|_| anyhow!("The command handler is not running"))?;
+        done_rx.await
+            .map_err(|_| anyhow!("The command handler stopped without saving"))?
+            .map_err(|e| anyhow!("Failed to save data: {}", e))
+    });
+
+    match drained.await {
+        Ok(result) => result.map(|_| ()),
+        Err(_) => Err(anyhow!("Commands were not saved within {:?}", limit)),
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::Utc;
+
+use tokio::sync::mpsc::Sender;
+
+use thiserror::Error;
+
+use crate::source::SourceState;
+use crate::types::{Command, Datapoint};
+
+// Simulated seconds between readings, like a real station
+const STEP: f64 = 300.;
+const SECONDS_PER_DAY: f64 = 86400.;
+
+// xorshift64*, small and gives the same sequence for a seed everywhere
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        // The state must never be zero
+        Random((seed ^ 0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // Uniform in 0..1
+    fn uniform(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Standard normal through Box-Muller
+    fn normal(&mut self) -> f64 {
+        let u = 1. - self.uniform();
+        (-2. * u.ln()).sqrt() * (2. * PI * self.uniform()).cos()
+    }
+
+    fn exponential(&mut self, mean: f64) -> f64 {
+        -mean * (1. - self.uniform()).ln()
+    }
+}
+
+// Moves `value` towards `mean` with the correlation time `hours`, adding noise
+// so that the long term standard deviation is `deviation`
+fn revert(random: &mut Random, value: f64, mean: f64, deviation: f64, step_hours: f64, hours: f64) -> f64 {
+    let decay = (-step_hours / hours).exp();
+    mean + (value - mean) * decay + random.normal() * deviation * (1. - decay * decay).sqrt()
+}
+
+// Relative humidity from temperature and dew point using the Magnus formula
+fn relative_humidity(temperature: f64, dew_point: f64) -> f64 {
+    let magnus = |t: f64| (17.62 * t / (243.12 + t)).exp();
+    (100. * magnus(dew_point) / magnus(temperature)).clamp(5., 100.)
+}
+
+// The weather at one place. Everything is driven by a few slowly changing
+// states so that the series fit together, rain comes with low pressure,
+// high humidity and a smaller diurnal cycle
+pub struct Weather {
+    random: Random,
+    pub time: f64,
+    // Deviation from the seasonal temperature
+    anomaly: f64,
+    pressure: f64,
+    wind: f64,
+    // Seconds left of the current shower and its intensity in mm/h
+    rain_left: f64,
+    rain_rate: f64,
+    battery: f64,
+}
+
+impl Weather {
+    pub fn new(seed: u64, time: f64) -> Self {
+        Self {
+            random: Random::new(seed),
+            time,
+            anomaly: 0.,
+            pressure: 1013.,
+            wind: 3.,
+            rain_left: 0.,
+            rain_rate: 0.,
+            battery: 4.1,
+        }
+    }
+
+    // Advances the weather by `step` seconds and returns the readings at the new time
+    pub fn step(&mut self, step: f64) -> Vec<(&'static str, f32)> {
+        self.time += step;
+        let hours = step / 3600.;
+        let day_of_year = (self.time / SECONDS_PER_DAY) % 365.25;
+        let hour_of_day = (self.time % SECONDS_PER_DAY) / 3600.;
+        let random = &mut self.random;
+
+        self.anomaly = revert(random, self.anomaly, 0., 3., hours, 48.);
+        self.pressure = revert(random, self.pressure, 1013., 9., hours, 72.);
+        let storminess = ((1008. - self.pressure) / 4.).max(0.);
+        self.wind = revert(random, self.wind, 3. + storminess, 1.5, hours, 6.).max(0.);
+
+        if self.rain_left > 0. {
+            self.rain_left -= step;
+        }
+        else {
+            // Showers per hour, more likely when the pressure is low
+            let chance = 0.01 + 0.1 * ((1010. - self.pressure) / 20.).clamp(0., 1.);
+            if random.uniform() < chance * hours {
+                self.rain_left = random.exponential(2. * 3600.);
+                self.rain_rate = 0.3 + random.exponential(2.);
+            }
+        }
+        let raining = self.rain_left > 0.;
+
+        // Coldest in the middle of January and in the early morning
+        let seasonal = 7. - 10. * (2. * PI * (day_of_year - 15.) / 365.25).cos();
+        let diurnal_amplitude = if raining { 1. } else { 5. };
+        let diurnal = diurnal_amplitude * (2. * PI * (hour_of_day - 15.) / 24.).cos();
+        let temperature = seasonal + self.anomaly + diurnal + random.normal() * 0.2
+            - if raining { 2. } else { 0. };
+
+        // The air holds about the same amount of water over the day, so the
+        // humidity is highest when it is coldest
+        let dew_point = if raining {
+            temperature - 0.5
+        }
+        else {
+            seasonal + self.anomaly - diurnal_amplitude - 2.
+        };
+        let humidity = relative_humidity(temperature, dew_point) + random.normal();
+
+        let wind = (self.wind * (1. + 0.2 * random.normal())).max(0.);
+        let gust = wind * (1.3 + 0.5 * random.uniform());
+
+        let rain = if raining { self.rain_rate * hours * (0.5 + random.uniform()) } else { 0. };
+
+        // Drains faster in the cold, and is replaced when it runs out
+        self.battery -= (0.01 + 0.002 * (-temperature).max(0.)) * step / SECONDS_PER_DAY;
+        if self.battery < 3.3 {
+            self.battery = 4.2;
+        }
+        let battery = self.battery + random.normal() * 0.005;
+
+        vec!(
+            ("temperature", temperature as f32),
+            ("humidity", humidity.clamp(0., 100.) as f32),
+            ("pressure", self.pressure as f32),
+            ("wind", wind as f32),
+            ("wind_gust", gust as f32),
+            ("rain", rain as f32),
+            ("battery", battery as f32),
+        )
+    }
+}
+
+fn random_seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos() as u64).unwrap_or(0)
+}
+
+#[derive(Error, Debug)]
+pub enum SimulatorError {
+    #[error("Acceleration {0} must be a finite number above 0")]
+    InvalidAcceleration(String),
+}
+
+// How much faster than real time the weather changes
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "f64")]
+pub struct Acceleration(f64);
+
+impl Acceleration {
+    // Real time between simulated readings
+    fn interval(self) -> Duration {
+        Duration::from_secs_f64(STEP / self.0)
+    }
+}
+
+impl TryFrom<f64> for Acceleration {
+    type Error = SimulatorError;
+
+    // Tiny accelerations give intervals that do not fit in a Duration
+    fn try_from(acceleration: f64) -> Result<Self, Self::Error> {
+        if acceleration.is_finite() && Duration::try_from_secs_f64(STEP / acceleration).is_ok() {
+            Ok(Acceleration(acceleration))
+        }
+        else {
+            Err(SimulatorError::InvalidAcceleration(format!("{:?}", acceleration)))
+        }
+    }
+}
+
+impl FromStr for Acceleration {
+    type Err = SimulatorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<f64>()
+            .map_err(|_| SimulatorError::InvalidAcceleration(s.to_string()))
+            .and_then(Acceleration::try_from)
+    }
+}
+
+impl fmt::Display for Acceleration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct SimulatorConfig {
+    // Random if not set
+    pub seed: Option<u64>,
+    #[serde(default = "default_acceleration")]
+    pub acceleration: Acceleration,
+}
+
+fn default_acceleration() -> Acceleration { Acceleration(1.) }
+
+// Sends simulated readings starting now. With an `acceleration` above 1 the
+// simulated time runs ahead of the real time
+pub fn run_simulator(tx: Sender<Command>, config: &SimulatorConfig, state: SourceState) -> JoinHandle<()> {
+    let seed = config.seed.unwrap_or_else(random_seed);
+    let acceleration = config.acceleration;
+    info!("Simulating weather with seed {} at {}x speed", seed, acceleration);
+    thread::spawn(move || {
+        let mut weather = Weather::new(seed, Utc::now().timestamp() as f64);
+        loop {
+            thread::sleep(acceleration.interval());
+            if state.is_stopped() {
+                return;
+            }
+            for (name, value) in weather.step(STEP) {
+                let command = Command::AddDatapoint(name.to_string(), value, Some(weather.time));
+                if tx.blocking_send(command).is_err() {
+                    return;
+                }
+            }
+        }
+    })
+}
+
+// Simulated history for the `days` up to now
+pub fn backfill(seed: u64, days: u32) -> HashMap<String, Vec<Datapoint>> {
+    let now = Utc::now().timestamp() as f64;
+    let mut weather = Weather::new(seed, now - days as f64 * SECONDS_PER_DAY);
+    let mut readings = HashMap::<String, Vec<Datapoint>>::new();
+    while weather.time + STEP <= now {
+        for (name, value) in weather.step(STEP) {
+            readings.entry(name.to_string())
+                .or_default()
+                .push(Datapoint::new(weather.time, value));
+        }
+    }
+    readings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(seed: u64, steps: usize) -> Vec<Vec<(&'static str, f32)>> {
+        let mut weather = Weather::new(seed, 1_600_000_000.);
+        (0..steps).map(|_| weather.step(STEP)).collect()
+    }
+
+    #[test]
+    fn seeds_give_the_same_sequence() {
+        // Pins the generator, a change to it changes every seeded simulation
+        let mut random = Random::new(1);
+        assert_eq!(
+            (random.next(), random.next(), random.next()),
+            (0x0d83_b3e2_9a21_487a, 0x54c4_4c79_f1fe_9d67, 0xa845_f342_007a_0e78)
+        );
+
+        assert_eq!(series(7, 1000), series(7, 1000));
+        assert_ne!(series(7, 1000), series(8, 1000));
+    }
+
+    #[test]
+    fn readings_stay_in_range() {
+        // A year of readings
+        for readings in series(3, 105_000) {
+            for (name, value) in readings {
+                let range = match name {
+                    "temperature" => -40.0..=45.0,
+                    "humidity" => 0.0..=100.0,
+                    "battery" => 3.2..=4.3,
+                    _ => 0.0..=f32::MAX,
+                };
+                assert!(range.contains(&value), "{} is {}", name, value);
+            }
+        }
+    }
+
+    #[test]
+    fn acceleration() {
+        assert_eq!("2.5".parse::<Acceleration>().unwrap(), Acceleration(2.5));
+        assert_eq!(Acceleration(2.).interval(), Duration::from_secs(150));
+        for invalid in &["0", "-1", "inf", "NaN", "1e-300", "fast", ""] {
+            assert!(invalid.parse::<Acceleration>().is_err(), "{} was accepted", invalid);
+        }
+
+        let config = |toml: &str| toml::from_str::<SimulatorConfig>(toml);
+        assert_eq!(config("seed = 1").unwrap().acceleration, Acceleration(1.));
+        assert_eq!(config("acceleration = 60").unwrap().acceleration, Acceleration(60.));
+        assert!(config("acceleration = 0").is_err());
+        assert!(config("acceleration = -2.0").is_err());
+        assert!(config("acceleration = nan").is_err());
+    }
+}
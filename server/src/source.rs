@@ -0,0 +1,445 @@
+use std::collections::HashSet;
+use std::net::{TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+
+use crate::config::SharedSeriesConfig;
+use crate::error::Result;
+use crate::mqtt::{self, MqttConfig};
+#[cfg(feature = "raspi_nrf")]
+use crate::nrf24l01_reader::{self, NrfConfig};
+use crate::postcard_handler;
+use crate::protocol::Ingest;
+use crate::serial_handler::{self, SerialConfig};
+use crate::simulator::{self, SimulatorConfig};
+use crate::tcp_handler;
+use crate::types::{ReadingCollection, SharedSubscribers};
+use crate::udp_handler::{self, UdpConfig};
+
+#[derive(Error, Debug)]
+pub enum SourceError {
+    #[error("There are several sources named {0}, give them different names")]
+    DuplicateName(String),
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceStatus {
+    Running,
+    // Waiting for a device or broker to become available
+    Connecting,
+    Stopped,
+    // Stopped on its own, the reason is in the log
+    Failed,
+}
+
+// Everything sources need to feed data into the server
+#[derive(Clone)]
+pub struct SourceContext {
+    pub ingest: Ingest,
+    pub readings: ReadingCollection,
+    pub series: SharedSeriesConfig,
+    // Sources that want to know about stored datapoints add themselves here
+    pub subscribers: SharedSubscribers,
+}
+
+// Something that produces commands, like a listener or a radio
+pub trait DataSource: Send {
+    // Starts the source in the background. Fails if the source can not run,
+    // for example when its port is taken
+    fn start(&mut self, context: &SourceContext) -> Result<()>;
+    // Keeps track of what `start` started
+    fn runner(&self) -> &Runner;
+
+    fn name(&self) -> &str {
+        &self.runner().name
+    }
+
+    fn stop(&mut self) {
+        self.runner().stop();
+    }
+
+    fn status(&self) -> SourceStatus {
+        self.runner().status()
+    }
+}
+
+pub type SharedSources = Arc<Mutex<Vec<Box<dyn DataSource>>>>;
+
+// Flags shared between a source and the task or threads that run it
+#[derive(Clone, Default)]
+pub struct SourceState {
+    stopped: Arc<AtomicBool>,
+    connecting: Arc<AtomicBool>,
+}
+
+impl SourceState {
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed)
+    }
+
+    // Sources that connect to something report whether they currently are
+    pub fn set_connecting(&self, connecting: bool) {
+        self.connecting.store(connecting, Ordering::Relaxed)
+    }
+}
+
+enum Running {
+    Task(tokio::task::JoinHandle<()>),
+    Thread(std::thread::JoinHandle<()>),
+}
+
+// Keeps track of a started source
+pub struct Runner {
+    name: String,
+    running: Option<Running>,
+    state: SourceState,
+}
+
+impl Runner {
+    fn new(name: String) -> Self {
+        Self { name, running: None, state: SourceState::default() }
+    }
+
+    fn run_task(&mut self, task: tokio::task::JoinHandle<()>) {
+        self.running = Some(Running::Task(task));
+    }
+
+    fn run_thread(&mut self, thread: std::thread::JoinHandle<()>) {
+        self.running = Some(Running::Thread(thread));
+    }
+
+    // Tasks are cancelled right away while threads stop the next time they
+    // check the state
+    fn stop(&self) {
+        self.state.stop();
+        if let Some(Running::Task(task)) = &self.running {
+            task.abort();
+        }
+    }
+
+    fn status(&self) -> SourceStatus {
+        let finished = match &self.running {
+            None => return SourceStatus::Stopped,
+            Some(Running::Task(task)) => task.is_finished(),
+            Some(Running::Thread(thread)) => thread.is_finished(),
+        };
+        if self.state.is_stopped() {
+            SourceStatus::Stopped
+        }
+        else if finished {
+            SourceStatus::Failed
+        }
+        else if self.state.connecting.load(Ordering::Relaxed) {
+            SourceStatus::Connecting
+        }
+        else {
+            SourceStatus::Running
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ListenerConfig {
+    #[serde(default = "default_address")]
+    pub address: String,
+    pub port: u16,
+}
+
+pub fn default_address() -> String {
+    "0.0.0.0".into()
+}
+
+impl ListenerConfig {
+    fn bind(&self) -> Result<tokio::net::TcpListener> {
+        let listener = TcpListener::bind((self.address.as_str(), self.port))?;
+        listener.set_nonblocking(true)?;
+        Ok(tokio::net::TcpListener::from_std(listener)?)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SourceKind {
+    // Lines over TCP
+    Tcp(ListenerConfig),
+    // Lines in UDP datagrams
+    Udp(UdpConfig),
+    // Length prefixed postcard encoded common::Message frames over TCP
+    Postcard(ListenerConfig),
+    // Stations connected over USB or UART
+    Serial(SerialConfig),
+    Mqtt(MqttConfig),
+    #[cfg(feature = "raspi_nrf")]
+    Nrf(NrfConfig),
+    // Fake readings for trying things out
+    Simulator(SimulatorConfig),
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct SourceConfig {
+    // Shown in the log and the health report, defaults to the kind
+    pub name: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub kind: SourceKind,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl SourceConfig {
+    pub fn name(&self) -> &str {
+        if let Some(name) = &self.name {
+            return name;
+        }
+        match self.kind {
+            SourceKind::Tcp(_) => "tcp",
+            SourceKind::Udp(_) => "udp",
+            SourceKind::Postcard(_) => "postcard",
+            SourceKind::Serial(_) => "serial",
+            SourceKind::Mqtt(_) => "mqtt",
+            #[cfg(feature = "raspi_nrf")]
+            SourceKind::Nrf(_) => "nrf",
+            SourceKind::Simulator(_) => "simulator",
+        }
+    }
+
+    fn build(&self) -> Box<dyn DataSource> {
+        let runner = Runner::new(self.name().to_string());
+        match &self.kind {
+            SourceKind::Tcp(config) => Box::new(TcpSource { config: config.clone(), runner }),
+            SourceKind::Udp(config) => Box::new(UdpSource { config: config.clone(), runner }),
+            SourceKind::Postcard(config) => Box::new(PostcardSource { config: config.clone(), runner }),
+            SourceKind::Serial(config) => Box::new(SerialSource { config: config.clone(), runner }),
+            SourceKind::Mqtt(config) => Box::new(MqttSource { config: config.clone(), runner }),
+            #[cfg(feature = "raspi_nrf")]
+            SourceKind::Nrf(config) => Box::new(NrfSource { config: config.clone(), runner }),
+            SourceKind::Simulator(config) => Box::new(SimulatorSource { config: config.clone(), runner }),
+        }
+    }
+}
+
+// Builds the enabled sources without starting them
+pub fn build_sources(configs: &[SourceConfig]) -> Result<Vec<Box<dyn DataSource>>> {
+    let mut names = HashSet::new();
+    for config in configs {
+        if !names.insert(config.name()) {
+            Err(SourceError::DuplicateName(config.name().to_string()))?
+        }
+    }
+
+    Ok(configs.iter()
+        .filter(|config| {
+            if !config.enabled {
+                info!("Source {} is disabled", config.name());
+            }
+            config.enabled
+        })
+        .map(SourceConfig::build)
+        .collect())
+}
+
+struct TcpSource {
+    config: ListenerConfig,
+    runner: Runner,
+}
+
+impl DataSource for TcpSource {
+    fn runner(&self) -> &Runner {
+        &self.runner
+    }
+
+    fn start(&mut self, context: &SourceContext) -> Result<()> {
+        let listener = self.config.bind()?;
+        info!("Listening for lines on TCP port {}", self.config.port);
+        self.runner.run_task(tokio::spawn(tcp_handler::tcp_handler(listener, context.ingest.clone())));
+        Ok(())
+    }
+}
+
+struct UdpSource {
+    config: UdpConfig,
+    runner: Runner,
+}
+
+impl DataSource for UdpSource {
+    fn runner(&self) -> &Runner {
+        &self.runner
+    }
+
+    fn start(&mut self, context: &SourceContext) -> Result<()> {
+        let socket = UdpSocket::bind((self.config.address.as_str(), self.config.port))?;
+        socket.set_nonblocking(true)?;
+        let socket = tokio::net::UdpSocket::from_std(socket)?;
+        info!("Listening for UDP datagrams on port {}", self.config.port);
+        self.runner.run_task(udp_handler::run_udp_handler(socket, context.ingest.clone(), self.config.dedup));
+        Ok(())
+    }
+}
+
+struct PostcardSource {
+    config: ListenerConfig,
+    runner: Runner,
+}
+
+impl DataSource for PostcardSource {
+    fn runner(&self) -> &Runner {
+        &self.runner
+    }
+
+    fn start(&mut self, context: &SourceContext) -> Result<()> {
+        let listener = self.config.bind()?;
+        info!("Listening for postcard frames on port {}", self.config.port);
+        self.runner.run_task(postcard_handler::run_postcard_handler(listener, context.ingest.clone()));
+        Ok(())
+    }
+}
+
+struct SerialSource {
+    config: SerialConfig,
+    runner: Runner,
+}
+
+impl DataSource for SerialSource {
+    fn runner(&self) -> &Runner {
+        &self.runner
+    }
+
+    fn start(&mut self, context: &SourceContext) -> Result<()> {
+        self.runner.run_task(serial_handler::run_serial_handler(
+            self.config.clone(),
+            context.ingest.clone(),
+            self.runner.state.clone()
+        ));
+        Ok(())
+    }
+}
+
+struct MqttSource {
+    config: MqttConfig,
+    runner: Runner,
+}
+
+impl DataSource for MqttSource {
+    fn runner(&self) -> &Runner {
+        &self.runner
+    }
+
+    fn start(&mut self, context: &SourceContext) -> Result<()> {
+        let (accepted_tx, accepted_rx) = std::sync::mpsc::channel();
+        context.subscribers.lock().unwrap().push(accepted_tx);
+
+        let mut known_series = context.readings.read().unwrap().keys().cloned().collect::<Vec<_>>();
+        known_series.extend(context.series.read().unwrap().keys().cloned());
+
+        self.runner.run_thread(mqtt::run_mqtt(
+            self.config.clone(),
+            Arc::clone(&context.series),
+            known_series,
+            context.ingest.clone(),
+            // Sources are started from within the runtime
+            tokio::runtime::Handle::current(),
+            accepted_rx,
+            self.runner.state.clone()
+        ));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "raspi_nrf")]
+struct NrfSource {
+    config: NrfConfig,
+    runner: Runner,
+}
+
+#[cfg(feature = "raspi_nrf")]
+impl DataSource for NrfSource {
+    fn runner(&self) -> &Runner {
+        &self.runner
+    }
+
+    fn start(&mut self, context: &SourceContext) -> Result<()> {
+        self.runner.run_thread(nrf24l01_reader::run_nrf_reader(
+            self.config.clone(),
+            context.ingest.clone(),
+            // Sources are started from within the runtime
+            tokio::runtime::Handle::current(),
+            self.runner.state.clone()
+        ));
+        Ok(())
+    }
+}
+
+struct SimulatorSource {
+    config: SimulatorConfig,
+    runner: Runner,
+}
+
+impl DataSource for SimulatorSource {
+    fn runner(&self) -> &Runner {
+        &self.runner
+    }
+
+    fn start(&mut self, context: &SourceContext) -> Result<()> {
+        self.runner.run_thread(simulator::run_simulator(
+            context.ingest.tx.clone(),
+            &self.config,
+            self.runner.state.clone()
+        ));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::time::{sleep, timeout};
+
+    use crate::protocol::Ingest;
+
+    fn context() -> SourceContext {
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+        SourceContext {
+            ingest: Ingest::for_test(vec!(), tx),
+            readings: Default::default(),
+            series: Default::default(),
+            subscribers: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn stopping_closes_connections() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = SourceConfig {
+            name: None,
+            enabled: true,
+            kind: SourceKind::Tcp(ListenerConfig { address: "127.0.0.1".into(), port }),
+        };
+        let mut source = config.build();
+        source.start(&context()).unwrap();
+        assert_eq!(source.status(), SourceStatus::Running);
+
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        // Gives the listener time to accept the connection
+        sleep(Duration::from_millis(100)).await;
+
+        source.stop();
+        let read = timeout(Duration::from_secs(1), stream.read(&mut [0; 16])).await
+            .expect("The connection was left open");
+        assert_eq!(read.unwrap(), 0);
+        assert_eq!(source.status(), SourceStatus::Stopped);
+    }
+}
@@ -1,71 +1,185 @@
-use std::sync::mpsc::Sender;
-use std::net::TcpListener;
-
-use std::sync::{Mutex, Arc};
-use std::thread;
-
-use std::io::Read;
-
-use crate::types::Command;
-use crate::constants::OPERATION_PREFIX;
-
-pub fn tcp_handler(listener: TcpListener, tx_arc_mutex: Arc<Mutex<Sender<Command>>>) {
-    for stream in listener.incoming() {
-        info!("New connection");
-
-        let tx_arc_mutex = Arc::clone(&tx_arc_mutex);
-        thread::spawn(move || {
-            let mut stream = stream.unwrap();
+use std::time::Duration;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::error::Result;
+use crate::protocol::{self, Ingest, ParseError, Session};
+
+// Lines longer than this are discarded rather than buffered
+const MAX_LINE_LENGTH: usize = 1024;
+// Connections that stay silent for this long are closed
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Connections run in a JoinSet so that they are aborted together with the listener
+pub async fn tcp_handler(
+    listener: TcpListener,
+    ingest: Ingest
+) {
+    let mut connections = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            // Forgets connections that have been closed
+            Some(_) = connections.join_next() => continue,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept connection: {:?}", e);
+                continue;
+            }
+        };
+        info!("New connection from {}", peer);
 
-            let mut buffer = vec!();
-            stream.read_to_end(&mut buffer).unwrap();
+        let ingest = ingest.clone();
+        connections.spawn(async move {
+            if let Err(e) = handle_connection(stream, &ingest).await {
+                warn!("Connection closed with error: {:?}", e);
+            }
+        });
+    }
+}
 
-            let message = String::from_utf8(buffer).unwrap();
-            info!("Got message: {}", message);
+// Reads up to and without `delimiter`. Returns None at EOF
+pub async fn read_frame(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    delimiter: u8
+) -> std::io::Result<Option<std::result::Result<Vec<u8>, ParseError>>> {
+    let mut buffer = vec!();
+    let read = (&mut *reader)
+        .take(MAX_LINE_LENGTH as u64 + 1)
+        .read_until(delimiter, &mut buffer)
+        .await?;
+
+    if read == 0 {
+        return Ok(None);
+    }
+    if buffer.last() == Some(&delimiter) {
+        buffer.pop();
+    }
+    else if buffer.len() > MAX_LINE_LENGTH {
+        skip_frame(reader, delimiter).await?;
+        return Ok(Some(Err(ParseError::TooLong(MAX_LINE_LENGTH))));
+    }
 
+    Ok(Some(Ok(buffer)))
+}
 
-            let command = if message.chars().peekable().peek() == Some(&OPERATION_PREFIX) {
-                // Handle operation
-                parse_operation(&message)
+// Skips up to and including `delimiter`, one buffer of the reader at a time
+async fn skip_frame(reader: &mut (impl AsyncBufRead + Unpin), delimiter: u8) -> std::io::Result<()> {
+    loop {
+        let buffer = reader.fill_buf().await?;
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        match buffer.iter().position(|byte| *byte == delimiter) {
+            Some(index) => {
+                reader.consume(index + 1);
+                return Ok(());
             }
-            else {
-                let (name, value, timestamp) = handle_reading(&message);
-                Some(Command::AddDatapoint(name, value, timestamp))
-            };
-
-            if let Some(command) = command {
-                tx_arc_mutex.lock().unwrap().send(command).unwrap();
+            None => {
+                let length = buffer.len();
+                reader.consume(length);
             }
-        });
+        }
     }
 }
-fn handle_reading(message: &str) -> (String, f32, Option<f64>) {
-    let split = message.split(':').collect::<Vec<_>>();
 
-    info!("got message: {}", message);
+// Reads one line without the trailing newline. Returns None at EOF
+pub async fn read_line(
+    reader: &mut (impl AsyncBufRead + Unpin)
+) -> std::io::Result<Option<std::result::Result<String, ParseError>>> {
+    Ok(read_frame(reader, b'\n').await?.map(|frame| {
+        frame.and_then(|bytes| String::from_utf8(bytes).map_err(|_| ParseError::InvalidUtf8))
+    }))
+}
 
-    let name = split[0].to_string();
-    let value = split[1].to_string().parse::<f32>().unwrap();
-    let timestamp = split.get(2).and_then(|timestamp_str| {
-        match timestamp_str.parse::<f64>() {
-            Ok(val) => Some(val),
+async fn handle_connection(
+    stream: TcpStream,
+    ingest: &Ingest
+) -> Result<()> {
+    let mut session = Session::new(stream.peer_addr()?.ip().to_string());
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = Some(BufWriter::new(writer));
+
+    // The last line of a connection does not need to end with a newline
+    let mut line_number = 0;
+    while let Some(line) = timeout(READ_TIMEOUT, read_line(&mut reader)).await?? {
+        line_number += 1;
+        let reply = match line {
+            Ok(line) => protocol::handle_line(&line, line_number, &mut session, ingest).await,
             Err(e) => {
-                println!("Failed to parse {} as a timestamp, ignoring. {}", timestamp_str, e);
-                None
+                warn!("Ignoring bad line: {}", e);
+                ingest.stats.lock().unwrap().record::<()>(&Err(e.clone()));
+                Some(format!("ERR {} {}", line_number, e))
+            }
+        };
+
+        // Replies are sent once all lines that have arrived are handled
+        if let Some(stream) = &mut writer {
+            if let Err(e) = send_reply(stream, reply, reader.buffer().is_empty()).await {
+                // Older stations close the connection without waiting for
+                // replies, so failing to send one is not an error
+                debug!("Failed to send reply, not sending more: {:?}", e);
+                writer = None;
             }
         }
-    });
+    }
 
-    (name, value, timestamp)
+    if let Some(stream) = &mut writer {
+        let _ = stream.flush().await;
+    }
+    Ok(())
 }
 
-fn parse_operation(message: &str) -> Option<Command> {
-    let without_prefix = message.chars().skip(1).collect::<String>();
+async fn send_reply(
+    stream: &mut (impl AsyncWrite + Unpin),
+    reply: Option<String>,
+    flush: bool
+) -> std::io::Result<()> {
+    if let Some(reply) = reply {
+        stream.write_all(format!("{}\n", reply).as_bytes()).await?;
+    }
+    if flush {
+        stream.flush().await?;
+    }
+    Ok(())
+}
 
-    let split = without_prefix.split(':').collect::<Vec<_>>();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn long_lines_are_skipped() {
+        let mut input = vec!(b'a'; MAX_LINE_LENGTH * 5);
+        input.extend_from_slice(b"\ntemperature:21.5\n");
+        let mut reader = BufReader::with_capacity(64, input.as_slice());
+
+        let skipped = read_frame(&mut reader, b'\n').await.unwrap();
+        assert!(matches!(skipped, Some(Err(ParseError::TooLong(MAX_LINE_LENGTH)))));
+        let next = read_frame(&mut reader, b'\n').await.unwrap();
+        assert_eq!(next.unwrap().unwrap(), b"temperature:21.5");
+        assert!(read_frame(&mut reader, b'\n').await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn last_line_may_end_without_delimiter() {
+        let mut reader = BufReader::new(&b"a:1\nb:2"[..]);
+        assert_eq!(read_frame(&mut reader, b'\n').await.unwrap().unwrap().unwrap(), b"a:1");
+        assert_eq!(read_frame(&mut reader, b'\n').await.unwrap().unwrap().unwrap(), b"b:2");
+        assert!(read_frame(&mut reader, b'\n').await.unwrap().is_none());
+    }
 
-    match split[0] {
-        "reset" => Some(Command::Reset(split[1].to_string())),
-        _ => None
+    #[tokio::test]
+    async fn long_lines_at_the_end_are_skipped() {
+        let input = vec!(0xff; MAX_LINE_LENGTH * 2);
+        let mut reader = BufReader::new(input.as_slice());
+        assert!(matches!(read_frame(&mut reader, 0).await.unwrap(), Some(Err(ParseError::TooLong(_)))));
+        assert!(read_frame(&mut reader, 0).await.unwrap().is_none());
     }
 }
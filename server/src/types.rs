@@ -1,18 +1,98 @@
 use std::collections::hash_map::HashMap;
-use std::sync::{Mutex, Arc};
+use std::sync::{Mutex, Arc, RwLock};
+use std::sync::mpsc::Sender;
 
-#[derive(Serialize, Deserialize)]
+use tokio::sync::oneshot;
+
+use thiserror::Error;
+
+use crate::events::Event;
+use crate::identity::Identity;
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Datapoint {
     pub timestamp: f64,
-    pub value: f32
+    pub value: f32,
+    // Number of values with this timestamp that `value` is the mean of, see
+    // DuplicatePolicy::Average
+    #[serde(default = "one_sample", skip_serializing_if = "is_one_sample")]
+    pub samples: u32,
+}
+
+fn one_sample() -> u32 { 1 }
+fn is_one_sample(samples: &u32) -> bool { *samples == 1 }
+
+impl Datapoint {
+    pub fn new(timestamp: f64, value: f32) -> Self {
+        Self { timestamp, value, samples: 1 }
+    }
+}
+
+// A datapoint that passed filtering and was stored. `value` is calibrated
+#[derive(Clone, Debug)]
+pub struct AcceptedDatapoint {
+    pub name: String,
+    pub raw: f32,
+    pub value: f32,
 }
 
+// Notified about every accepted datapoint
+pub type SharedSubscribers = Arc<Mutex<Vec<Sender<AcceptedDatapoint>>>>;
+
+// Why a command failed
+#[derive(Error, Clone, Debug, PartialEq)]
+pub enum Failure {
+    #[error("{0}")]
+    Error(String),
+    // The value was quarantined by the filter, sending it again gives the
+    // same result
+    #[error("{0}")]
+    Rejected(String),
+}
+
+impl From<String> for Failure {
+    fn from(reason: String) -> Self {
+        Failure::Error(reason)
+    }
+}
+
+impl From<&str> for Failure {
+    fn from(reason: &str) -> Self {
+        Failure::Error(reason.to_string())
+    }
+}
+
+// The outcome of a command, with the reason in case it failed. Some commands
+// answer with a value, like the list of series
+pub type Reply = Result<Option<String>, Failure>;
+
 pub enum Command {
     Reset(String), // Removes all data for the specified reading
-    AddDatapoint(String, f32, Option<f64>)
+    AddDatapoint(String, f32, Option<f64>),
+    Rename(String, String),
+    // Removes the datapoints of a series between two optional timestamps
+    DeleteRange(String, Option<f64>, Option<f64>),
+    ListSeries,
+    // Saves the data and metadata right away instead of waiting for the logger
+    Snapshot,
+    // Sets a metadata key of a series, an empty value removes the key
+    SetMetadata(String, String, String),
+    Time,
+    // Something that happened on a station, like an error
+    Event(Event),
+    // A station announcing itself
+    Hello(Identity),
+    // Runs the inner command and sends back the outcome once it is committed
+    WithReply(Box<Command>, oneshot::Sender<Reply>),
+    // Handles the commands that are already queued, saves everything and
+    // stops the command handler
+    Shutdown(oneshot::Sender<Reply>),
 }
 
-pub type ReadingCollection = Arc<Mutex<HashMap<String, Vec<Datapoint>>>>;
+// Web requests only read, so they do not have to wait for each other
+pub type ReadingCollection = Arc<RwLock<HashMap<String, Vec<Datapoint>>>>;
+// Free form key value pairs for each series, like location or sensor model
+pub type MetadataCollection = Arc<Mutex<HashMap<String, HashMap<String, String>>>>;
 
 
 
@@ -0,0 +1,150 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use crate::protocol::{self, Ingest, ParseError, Session};
+
+// Largest datagram that is accepted. Longer ones are dropped as a whole since
+// their last line would be cut off and could still parse
+const MAX_DATAGRAM_SIZE: usize = 2048;
+// Sequence numbers are forgotten after this long so that a rebooted station
+// which starts counting from scratch is not mistaken for a retransmission
+const DEDUP_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const DEDUP_LENGTH: usize = 256;
+
+// Replies to recently seen sequence numbers. Duplicates get the original
+// reply again since it was most likely the reply that got lost. Only OK and REJ
+// are kept, a line that got ERR is handled again when it is sent again
+#[derive(Default)]
+struct RecentReplies {
+    order: VecDeque<(IpAddr, u64)>,
+    replies: HashMap<(IpAddr, u64), (Instant, String)>,
+}
+
+impl RecentReplies {
+    fn get(&self, key: &(IpAddr, u64)) -> Option<&String> {
+        self.replies.get(key)
+            .filter(|(time, _)| time.elapsed() < DEDUP_TIMEOUT)
+            .map(|(_, reply)| reply)
+    }
+
+    fn insert(&mut self, key: (IpAddr, u64), reply: String) {
+        if !(reply.starts_with("OK") || reply.starts_with("REJ")) {
+            return;
+        }
+        if self.replies.insert(key, (Instant::now(), reply)).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > DEDUP_LENGTH {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct UdpConfig {
+    #[serde(default = "crate::source::default_address")]
+    pub address: String,
+    pub port: u16,
+    // Drop datagrams with a sequence number that was recently seen
+    #[serde(default)]
+    pub dedup: bool,
+}
+
+pub fn run_udp_handler(
+    socket: UdpSocket,
+    ingest: Ingest,
+    dedup: bool
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut recent = RecentReplies::default();
+        // One byte more than accepted to tell full datagrams from truncated ones
+        let mut buffer = [0; MAX_DATAGRAM_SIZE + 1];
+        loop {
+            let (length, peer) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("Failed to receive UDP datagram: {:?}", e);
+                    continue;
+                }
+            };
+
+            if length > MAX_DATAGRAM_SIZE {
+                let error = ParseError::TooLong(MAX_DATAGRAM_SIZE);
+                warn!("Ignoring datagram from {}: {}", peer, error);
+                ingest.stats.lock().unwrap().record::<()>(&Err(error));
+                continue;
+            }
+            let message = match std::str::from_utf8(&buffer[..length]) {
+                Ok(message) => message,
+                Err(_) => {
+                    warn!("Ignoring datagram from {}: {}", peer, ParseError::InvalidUtf8);
+                    ingest.stats.lock().unwrap().record::<()>(&Err(ParseError::InvalidUtf8));
+                    continue;
+                }
+            };
+
+            // A hello only covers the rest of its datagram since there is no connection
+            let mut session = Session::new(peer.ip().to_string());
+            for (index, line) in message.lines().enumerate() {
+                let key = protocol::split_seq(line).ok()
+                    .and_then(|(seq, _)| seq)
+                    .map(|seq| (peer.ip(), seq))
+                    .filter(|_| dedup);
+
+                let reply = match key.and_then(|key| recent.get(&key).cloned()) {
+                    Some(reply) => {
+                        debug!("Ignoring duplicate {:?} from {}", line, peer);
+                        Some(reply)
+                    }
+                    None => protocol::handle_line(line, index as u64 + 1, &mut session, &ingest).await
+                };
+
+                if let Some(reply) = reply {
+                    if let Some(key) = key {
+                        recent.insert(key, reply.clone());
+                    }
+                    if let Err(e) = socket.send_to(format!("{}\n", reply).as_bytes(), peer).await {
+                        debug!("Failed to send reply to {}: {:?}", peer, e);
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn only_final_replies_are_kept() {
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut recent = RecentReplies::default();
+        recent.insert((peer, 1), "OK 1".into());
+        recent.insert((peer, 2), "REJ 2 spike".into());
+        recent.insert((peer, 3), "ERR 3 command handler is not running".into());
+
+        assert_eq!(recent.get(&(peer, 1)).map(String::as_str), Some("OK 1"));
+        assert_eq!(recent.get(&(peer, 2)).map(String::as_str), Some("REJ 2 spike"));
+        assert_eq!(recent.get(&(peer, 3)), None);
+    }
+
+    #[test]
+    fn old_replies_are_forgotten() {
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut recent = RecentReplies::default();
+        for seq in 0..DEDUP_LENGTH as u64 + 1 {
+            recent.insert((peer, seq), format!("OK {}", seq));
+        }
+        assert_eq!(recent.get(&(peer, 0)), None);
+        assert!(recent.get(&(peer, 1)).is_some());
+    }
+}
@@ -0,0 +1,207 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum UnitError {
+    #[error("Unknown unit {0}")]
+    Unknown(String),
+    #[error("Can not convert from {0} to {1}")]
+    Incompatible(Unit, Unit),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quantity {
+    Temperature,
+    Pressure,
+    Speed,
+    Length,
+    Voltage,
+    Humidity,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+    Pascal,
+    Hectopascal,
+    InchOfMercury,
+    MillimeterOfMercury,
+    MetersPerSecond,
+    KilometersPerHour,
+    MilesPerHour,
+    Knots,
+    Millimeter,
+    Inch,
+    Volt,
+    Percent,
+}
+
+impl Unit {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Unit::Celsius => "°C",
+            Unit::Fahrenheit => "°F",
+            Unit::Kelvin => "K",
+            Unit::Pascal => "Pa",
+            Unit::Hectopascal => "hPa",
+            Unit::InchOfMercury => "inHg",
+            Unit::MillimeterOfMercury => "mmHg",
+            Unit::MetersPerSecond => "m/s",
+            Unit::KilometersPerHour => "km/h",
+            Unit::MilesPerHour => "mph",
+            Unit::Knots => "kn",
+            Unit::Millimeter => "mm",
+            Unit::Inch => "in",
+            Unit::Volt => "V",
+            Unit::Percent => "%",
+        }
+    }
+
+    pub fn quantity(self) -> Quantity {
+        match self {
+            Unit::Celsius | Unit::Fahrenheit | Unit::Kelvin => Quantity::Temperature,
+            Unit::Pascal
+                | Unit::Hectopascal
+                | Unit::InchOfMercury
+                | Unit::MillimeterOfMercury => Quantity::Pressure,
+            Unit::MetersPerSecond
+                | Unit::KilometersPerHour
+                | Unit::MilesPerHour
+                | Unit::Knots => Quantity::Speed,
+            Unit::Millimeter | Unit::Inch => Quantity::Length,
+            Unit::Volt => Quantity::Voltage,
+            Unit::Percent => Quantity::Humidity,
+        }
+    }
+
+    // (scale, offset) such that `si = value * scale + offset`, where the SI
+    // units are K, Pa, m/s and mm
+    fn to_si(self) -> (f64, f64) {
+        match self {
+            Unit::Celsius => (1., 273.15),
+            Unit::Fahrenheit => (5. / 9., 273.15 - 32. * 5. / 9.),
+            Unit::Kelvin => (1., 0.),
+            Unit::Pascal => (1., 0.),
+            Unit::Hectopascal => (100., 0.),
+            Unit::InchOfMercury => (3386.389, 0.),
+            Unit::MillimeterOfMercury => (133.322_387, 0.),
+            Unit::MetersPerSecond => (1., 0.),
+            Unit::KilometersPerHour => (1. / 3.6, 0.),
+            Unit::MilesPerHour => (0.447_04, 0.),
+            Unit::Knots => (1852. / 3600., 0.),
+            Unit::Millimeter => (1., 0.),
+            Unit::Inch => (25.4, 0.),
+            Unit::Volt | Unit::Percent => (1., 0.),
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.symbol())
+    }
+}
+
+impl FromStr for Unit {
+    type Err = UnitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "°C" | "C" | "degC" | "celsius" => Unit::Celsius,
+            "°F" | "F" | "degF" | "fahrenheit" => Unit::Fahrenheit,
+            "K" | "kelvin" => Unit::Kelvin,
+            "Pa" => Unit::Pascal,
+            "hPa" | "mbar" => Unit::Hectopascal,
+            "inHg" => Unit::InchOfMercury,
+            "mmHg" => Unit::MillimeterOfMercury,
+            "m/s" | "mps" => Unit::MetersPerSecond,
+            "km/h" | "kph" => Unit::KilometersPerHour,
+            "mph" => Unit::MilesPerHour,
+            "kn" | "knots" | "kt" => Unit::Knots,
+            "mm" => Unit::Millimeter,
+            "in" => Unit::Inch,
+            "V" => Unit::Volt,
+            "%" => Unit::Percent,
+            other => return Err(UnitError::Unknown(other.to_string()))
+        })
+    }
+}
+
+impl TryFrom<String> for Unit {
+    type Error = UnitError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+pub fn convert(value: f32, from: Unit, to: Unit) -> Result<f32, UnitError> {
+    if from.quantity() != to.quantity() {
+        return Err(UnitError::Incompatible(from, to));
+    }
+    if from == to {
+        return Ok(value);
+    }
+
+    let (from_scale, from_offset) = from.to_si();
+    let (to_scale, to_offset) = to.to_si();
+    let si = value as f64 * from_scale + from_offset;
+    Ok(((si - to_offset) / to_scale) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_converts(value: f32, from: &str, to: &str, expected: f32) {
+        let converted = convert(value, from.parse().unwrap(), to.parse().unwrap()).unwrap();
+        assert!(
+            (converted - expected).abs() < 1e-3 * expected.abs().max(1.),
+            "{} {} is {} {}, expected {}", value, from, converted, to, expected
+        );
+    }
+
+    #[test]
+    fn temperature() {
+        assert_converts(0., "°C", "°F", 32.);
+        assert_converts(100., "°C", "°F", 212.);
+        assert_converts(-40., "°F", "°C", -40.);
+        assert_converts(0., "°C", "K", 273.15);
+        assert_converts(0., "K", "°F", -459.67);
+    }
+
+    #[test]
+    fn pressure() {
+        assert_converts(1013.25, "hPa", "inHg", 29.921);
+        assert_converts(30., "inHg", "hPa", 1015.917);
+        assert_converts(760., "mmHg", "hPa", 1013.25);
+        assert_converts(1., "mbar", "Pa", 100.);
+    }
+
+    #[test]
+    fn speed() {
+        assert_converts(10., "m/s", "mph", 22.369);
+        assert_converts(60., "mph", "km/h", 96.561);
+        assert_converts(36., "km/h", "m/s", 10.);
+        assert_converts(1., "kn", "km/h", 1.852);
+    }
+
+    #[test]
+    fn same_unit() {
+        assert_eq!(convert(21.5, Unit::Celsius, Unit::Celsius).unwrap(), 21.5);
+        assert_converts(1., "in", "mm", 25.4);
+    }
+
+    #[test]
+    fn invalid_units() {
+        assert!(matches!("furlongs".parse::<Unit>(), Err(UnitError::Unknown(_))));
+        assert!(matches!(convert(1., Unit::Celsius, Unit::Hectopascal), Err(UnitError::Incompatible(..))));
+        assert!(matches!(convert(1., Unit::Volt, Unit::Percent), Err(UnitError::Incompatible(..))));
+    }
+}
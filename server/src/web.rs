@@ -2,11 +2,29 @@ use simple_server::Server;
 use http::{header, StatusCode};
 use serde_json;
 use std::thread;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::Utc;
+
+use tokio::runtime::Handle;
 
 use std::fs::File;
 use std::io::prelude::*;
 
-use crate::types::ReadingCollection;
+use crate::types::{ReadingCollection, MetadataCollection, Failure};
+use crate::alerts::SharedAlertEngine;
+use crate::health::SharedHealthTracker;
+use crate::config::{SeriesConfig, SharedSeriesConfig, StationConfig};
+use crate::calibration;
+use crate::units::{self, Unit, UnitError};
+use crate::protocol::{self, Ingest, Session};
+use crate::influx::{self, InfluxError, Precision};
+use crate::postcard_handler;
+use crate::logger;
+use crate::events::{EventError, EventQuery, SharedEventStore};
+use crate::source::SharedSources;
 
 use color_anyhow::anyhow::Context;
 
@@ -18,31 +36,248 @@ pub enum WebError {
     NoSuchDataName(String),
     #[error("Unahndled uri: {0}")]
     UnhandledURI(String),
+    #[error("{0} has no declared unit to convert from")]
+    NoUnit(String),
+    #[error("Expected a POST request")]
+    NotPost,
+    #[error("Failed to store data: {0}")]
+    CommitFailed(String),
+    #[error("Invalid value {1:?} for {0}")]
+    InvalidQuery(String, String),
+    #[error("The server is shutting down")]
+    ShuttingDown,
 }
 
 // pub type Result<T> = std::result::Result<T, WebError>;
 pub type Result<T> = color_anyhow::anyhow::Result<T>;
 
+fn percent_decode(s: &str) -> String {
+    let mut bytes = vec!();
+    let mut input = s.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'%' => {
+                let hex = input.by_ref().take(2).collect::<Vec<_>>();
+                let decoded = std::str::from_utf8(&hex).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match decoded {
+                    Some(decoded) => bytes.push(decoded),
+                    None => {
+                        bytes.push(b'%');
+                        bytes.extend(hex);
+                    }
+                }
+            }
+            b'+' => bytes.push(b' '),
+            other => bytes.push(other)
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query.unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut split = pair.splitn(2, '=');
+            (
+                percent_decode(split.next().unwrap_or("")),
+                percent_decode(split.next().unwrap_or(""))
+            )
+        })
+        .collect()
+}
+
 fn handle_data_request_query(
     request_path_parts: &[&str],
-    readings: &ReadingCollection
+    query: &HashMap<String, String>,
+    readings: &ReadingCollection,
+    series: &HashMap<String, SeriesConfig>
 ) -> Result<String> {
     // If a datafield is specified, return that data
     if let Some(name) = request_path_parts.get(2) {
-        let readings = readings.lock().unwrap();
-        let data = readings.get(*name)
-            .ok_or(WebError::NoSuchDataName(name.to_string()))?;
+        // Stored values are raw, so calibration is applied unless the raw values are requested
+        let calibrations = match series.get(*name) {
+            Some(config) if !query.contains_key("raw") => config.calibration.as_slice(),
+            _ => &[]
+        };
+        // The calibrated copy is encoded after the lock is released so that
+        // ingestion does not wait for it
+        let mut data = {
+            let readings = readings.read().unwrap();
+            let data = readings.get(*name)
+                .ok_or(WebError::NoSuchDataName(name.to_string()))?;
+            calibration::calibrate_series(calibrations, data)
+        };
+
+        if let Some(unit) = query.get("unit") {
+            let to = unit.parse::<Unit>()?;
+            let from = series.get(*name)
+                .and_then(|config| config.unit)
+                .ok_or_else(|| WebError::NoUnit(name.to_string()))?;
+            for point in &mut data {
+                point.value = units::convert(point.value, from, to)?;
+            }
+        }
 
         Ok(serde_json::to_string(&data).context("Failed to encode data")?)
     }
     // Otherwise return a list of available data
     else {
-        let readings = readings.lock().unwrap();
+        let readings = readings.read().unwrap();
         let available_data = readings.keys().collect::<Vec<_>>();
         Ok(serde_json::to_string(&available_data)?)
     }
 }
 
+fn handle_alerts_request(
+    request_path_parts: &[&str],
+    alerts: &SharedAlertEngine
+) -> Result<String> {
+    let alerts = alerts.lock().unwrap();
+    match request_path_parts.get(2) {
+        Some(&"active") => Ok(serde_json::to_string(&alerts.active().iter().map(|rule| &rule.name).collect::<Vec<_>>())?),
+        Some(&"") | None => Ok(serde_json::to_string(&alerts.history())?),
+        Some(other) => Err(WebError::UnhandledURI(other.to_string()).into())
+    }
+}
+
+fn handle_metadata_request(
+    request_path_parts: &[&str],
+    metadata: &MetadataCollection
+) -> Result<String> {
+    let metadata = metadata.lock().unwrap();
+    match request_path_parts.get(2) {
+        Some(&"") | None => Ok(serde_json::to_string(&*metadata)?),
+        Some(name) => {
+            let entries = metadata.get(*name)
+                .ok_or_else(|| WebError::NoSuchDataName(name.to_string()))?;
+            Ok(serde_json::to_string(entries)?)
+        }
+    }
+}
+
+fn query_value<T: std::str::FromStr>(query: &HashMap<String, String>, key: &str) -> Result<Option<T>> {
+    query.get(key)
+        .map(|value| value.parse().map_err(|_| WebError::InvalidQuery(key.to_string(), value.clone())))
+        .transpose()
+        .map_err(Into::into)
+}
+
+fn handle_events_request(
+    request_path_parts: &[&str],
+    query: &HashMap<String, String>,
+    events: &SharedEventStore
+) -> Result<String> {
+    let events = events.lock().unwrap();
+    match request_path_parts.get(2) {
+        Some(&"counts") => Ok(serde_json::to_string(events.counts())?),
+        Some(&"") | None => {
+            let query = EventQuery {
+                station: query.get("station").cloned(),
+                kind: query.get("kind").cloned(),
+                severity: query.get("severity").map(|s| s.parse()).transpose()?,
+                since: query_value(query, "since")?,
+                limit: query_value(query, "limit")?,
+            };
+            Ok(serde_json::to_string(&events.query(&query))?)
+        }
+        Some(other) => Err(WebError::UnhandledURI(other.to_string()).into())
+    }
+}
+
+fn handle_health_request(health: &SharedHealthTracker, sources: &SharedSources) -> Result<String> {
+    let mut report = health.lock().unwrap().report(Utc::now().timestamp() as f64);
+    report.sources = sources.lock().unwrap().iter()
+        .map(|source| (source.name().to_string(), source.status()))
+        .collect();
+    Ok(serde_json::to_string(&report)?)
+}
+
+// Writes are refused once the server shuts down, the data handler only
+// stores what was sent before that
+fn check_post(request: &simple_server::Request<Vec<u8>>, stopping: &AtomicBool) -> Result<()> {
+    if request.method() != http::Method::POST {
+        return Err(WebError::NotPost.into());
+    }
+    if stopping.load(Ordering::Relaxed) {
+        return Err(WebError::ShuttingDown.into());
+    }
+    Ok(())
+}
+
+// Accepts InfluxDB line protocol in the same way as the /write endpoint of InfluxDB
+fn handle_write_request(
+    request: &simple_server::Request<Vec<u8>>,
+    query: &HashMap<String, String>,
+    ingest: &Ingest,
+    stopping: &AtomicBool,
+    runtime: &Handle
+) -> Result<String> {
+    check_post(request, stopping)?;
+    let precision = match query.get("precision") {
+        Some(precision) => precision.parse()?,
+        None => Precision::Nanoseconds
+    };
+    let body = std::str::from_utf8(request.body()).context("Request body is not valid UTF-8")?;
+
+    // Nothing is stored unless the whole body parses
+    let mut commands = vec!();
+    for line in body.lines().filter(|line| !line.trim().is_empty() && !line.starts_with('#')) {
+        let parsed = influx::parse_line(&ingest.stations, line, precision).map_err(protocol::ParseError::from);
+        ingest.stats.lock().unwrap().record(&parsed);
+        commands.extend(parsed?);
+    }
+
+    runtime.block_on(Session::new("http").execute(commands, None, ingest))
+        .map_err(|e| WebError::CommitFailed(e.to_string()))?;
+    Ok(String::new())
+}
+
+// Accepts a body of length prefixed postcard frames, like the postcard TCP port
+fn handle_frames_request(
+    request: &simple_server::Request<Vec<u8>>,
+    ingest: &Ingest,
+    stopping: &AtomicBool,
+    runtime: &Handle
+) -> Result<String> {
+    check_post(request, stopping)?;
+    let frames = postcard_handler::split_frames(request.body())?;
+
+    // Nothing is stored unless all frames decode
+    let mut commands = vec!();
+    for frame in frames {
+        let decoded = postcard_handler::decode_frame(frame);
+        ingest.stats.lock().unwrap().record(&decoded);
+        commands.push(decoded?);
+    }
+
+    // One at a time since a hello applies to the frames after it
+    let mut session = Session::new("http");
+    runtime.block_on(async {
+        for (command, auth) in commands {
+            session.execute(vec!(command), auth, ingest).await?;
+        }
+        Ok::<_, Failure>(())
+    }).map_err(|e| WebError::CommitFailed(e.to_string()))?;
+    Ok(String::new())
+}
+
+fn handle_export_request(
+    query: &HashMap<String, String>,
+    readings: &ReadingCollection,
+    series: &HashMap<String, SeriesConfig>,
+    stations: &[StationConfig]
+) -> Result<String> {
+    let precision = match query.get("precision") {
+        Some(precision) => precision.parse()?,
+        None => Precision::Nanoseconds
+    };
+    let readings = logger::copy_readings(readings);
+    Ok(influx::export(&readings, series, stations, precision, query.contains_key("raw")))
+}
+
 fn handle_index_request() -> color_anyhow::anyhow::Result<String> {
     let mut file = File::open("frontend/output/index.html")
         .context("Failed to open fronted/output/index.html")?;
@@ -53,26 +288,90 @@ fn handle_index_request() -> color_anyhow::anyhow::Result<String> {
     Ok(contents)
 }
 
-pub fn run_server(listen_address: String, port: u16, readings: ReadingCollection) {
+pub struct WebState {
+    pub readings: ReadingCollection,
+    pub alerts: SharedAlertEngine,
+    pub health: SharedHealthTracker,
+    pub series: SharedSeriesConfig,
+    pub metadata: MetadataCollection,
+    pub events: SharedEventStore,
+    pub sources: SharedSources,
+    // Used to store data written through the web interface
+    pub ingest: Ingest,
+    // Set when the server starts shutting down
+    pub stopping: Arc<AtomicBool>,
+    // The server runs on its own threads, writes are handed to the runtime
+    pub runtime: Handle,
+}
+
+pub fn run_server(listen_address: String, port: u16, state: WebState) {
     let server = Server::new(move |request, mut response| {
+        let WebState { readings, alerts, health, series, metadata, events, sources, ingest, stopping, runtime } = &state;
         let request_path = request.uri().path();
         let request_path_parts = request_path.split('/').collect::<Vec<_>>();
+        let query = parse_query(request.uri().query());
 
         let (handled, content_type) = match request_path_parts[1] {
             "" => {
                 (handle_index_request(), "text/html")
             }
             "data" => {
-                (handle_data_request_query(&request_path_parts, &readings), "text/plain")
+                (handle_data_request_query(&request_path_parts, &query, readings, &series.read().unwrap()), "text/plain")
+            }
+            "alerts" => {
+                (handle_alerts_request(&request_path_parts, alerts), "application/json")
+            }
+            "metadata" => {
+                (handle_metadata_request(&request_path_parts, metadata), "application/json")
+            }
+            "events" => {
+                (handle_events_request(&request_path_parts, &query, events), "application/json")
+            }
+            "health" => {
+                (handle_health_request(health, sources), "application/json")
+            }
+            "stats" => {
+                (serde_json::to_string(&*ingest.stats.lock().unwrap()).map_err(Into::into), "application/json")
+            }
+            // InfluxDB 1.x and 2.x clients use different paths
+            "write" | "api" if request_path.ends_with("/write") => {
+                (handle_write_request(&request, &query, ingest, stopping, runtime), "text/plain")
+            }
+            "frames" => {
+                (handle_frames_request(&request, ingest, stopping, runtime), "text/plain")
+            }
+            "export" => {
+                (handle_export_request(&query, readings, &series.read().unwrap(), &ingest.stations), "text/plain")
             }
             _ => (Err(WebError::UnhandledURI(request_path.to_string()).into()), "text/plain")
         };
 
         let request_response = match handled {
+            Ok(val) if val.is_empty() => {
+                response.status(StatusCode::NO_CONTENT);
+                val
+            }
             Ok(val) => val,
             Err(e) => {
                 log!(log::Level::Error, "{:#?}" ,e);
-                response.status(StatusCode::NOT_FOUND);
+                let status = if e.downcast_ref::<InfluxError>().is_some()
+                    || e.downcast_ref::<protocol::ParseError>().is_some()
+                    || e.downcast_ref::<EventError>().is_some()
+                    || e.downcast_ref::<UnitError>().is_some()
+                    || matches!(e.downcast_ref::<WebError>(), Some(WebError::InvalidQuery(..)))
+                {
+                    StatusCode::BAD_REQUEST
+                }
+                else if matches!(e.downcast_ref::<WebError>(), Some(WebError::CommitFailed(..))) {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+                else if matches!(e.downcast_ref::<WebError>(), Some(WebError::ShuttingDown)) {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+                else {
+                    StatusCode::NOT_FOUND
+                };
+                response.status(status);
                 format!("{}", e)
             }
         };